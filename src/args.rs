@@ -1,25 +1,87 @@
 use clap::{Error as ClapError, Arg, ArgMatches, App};
-use openssl::x509::{X509NameBuilder, X509Name, X509ReqBuilder};
+use openssl::x509::{X509Name, X509ReqBuilder};
 use openssl::error::ErrorStack as SslError;
 use openssl::asn1::Asn1Time;
 
 use openssl::stack::Stack;
 use openssl::x509::X509Extension;
-use openssl::x509::extension::{SubjectAlternativeName};
-use std::num::ParseIntError;
+use openssl::x509::extension::{ExtendedKeyUsage, KeyUsage, SubjectAlternativeName};
+use openssl::asn1::Asn1Object;
 use openssl::nid::Nid;
+use openssl::symm::Cipher;
+use openssl::pkey::Id;
+use std::num::ParseIntError;
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Error as IOError};
+use std::fs::File;
+use std::env;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use openssl::bn::BigNum;
+use crate::{KeySpec, EcCurve, Format, Validity, RevocationReason, Rdn, NameError, build_name, validity_is_ordered};
+use crate::db::Status;
+use crate::constraints::{NameConstraints, Subtree};
+use crate::der;
+use crate::output::Overwrite;
+use crate::time::parse_time;
+use crate::passphrase::{prompt_passphrase, strip_newline};
+use crate::verify::{Purpose, VerifyOptions};
 
 #[derive(Debug)]
 pub enum ParseError {
     Arg(ClapError),
     Ssl(SslError),
     ParseInt(ParseIntError),
+    IO(IOError),
+    /// An option value that is not valid, with a message naming the option
+    Invalid(String),
+    /// A subject that cannot be parsed
     Name(String),
+    /// A subjectAltName value that is not valid for its type
+    San(String),
+    /// A subject attribute longer than the upper bound of RFC 5280 for its type, with that bound
+    NameTooLong(&'static str, usize),
+    /// A subject attribute value breaking a rule of its type, with the rule
+    NameInvalid(&'static str, &'static str),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Arg(_) => write!(f, "invalid arguments"),
+            ParseError::Ssl(_) => write!(f, "invalid value"),
+            ParseError::ParseInt(_) => write!(f, "invalid number"),
+            ParseError::IO(_) => write!(f, "could not read a passphrase or password"),
+            ParseError::Invalid(msg) | ParseError::Name(msg) | ParseError::San(msg) => write!(f, "{}", msg),
+            ParseError::NameTooLong(field, max) => write!(f, "invalid subject: {} is longer than {} characters", field, max),
+            ParseError::NameInvalid(field, rule) => write!(f, "invalid subject: {} {}", field, rule),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Arg(err) => Some(err),
+            ParseError::Ssl(err) => Some(err),
+            ParseError::ParseInt(err) => Some(err),
+            ParseError::IO(err) => Some(err),
+            ParseError::Invalid(_) | ParseError::Name(_) | ParseError::San(_) |
+            ParseError::NameTooLong(..) | ParseError::NameInvalid(..) => None,
+        }
+    }
 }
 
 impl From<&str> for ParseError {
     fn from(x: &str) -> Self {
-        ParseError::Name(x.to_string())
+        ParseError::Invalid(x.to_string())
+    }
+}
+
+impl From<String> for ParseError {
+    fn from(x: String) -> Self {
+        ParseError::Invalid(x)
     }
 }
 
@@ -35,20 +97,55 @@ impl From<SslError> for ParseError {
     }
 }
 
+impl From<NameError> for ParseError {
+    fn from(x: NameError) -> Self {
+        match x {
+            NameError::OpenSSL(x) => ParseError::Ssl(x),
+            x => ParseError::Name(format!("invalid subject: {}", x)),
+        }
+    }
+}
+
 impl From<ParseIntError> for ParseError {
     fn from(x: ParseIntError) -> Self {
         ParseError::ParseInt(x)
     }
 }
 
+impl From<IOError> for ParseError {
+    fn from(x: IOError) -> Self {
+        ParseError::IO(x)
+    }
+}
+
+/// Name attributes settable one by one, in the order they appear in the subject
+const NAME_ATTRIBUTES: &[(&str, &str)] = &[
+    ("dc", "DC"),
+    ("co", "C"),
+    ("st", "ST"),
+    ("lo", "L"),
+    ("or", "O"),
+    ("ou", "OU"),
+    ("cn", "CN"),
+    ("sn", "serialNumber"),
+    ("em", "emailAddress"),
+];
+
 pub fn parser_name_builder<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
     app
+        .arg(
+            Arg::with_name("subject")
+                .long("subject")
+                .value_name("dn")
+                .help("Whole subject as /C=US/O=Example/CN=host or RFC 4514 CN=host,O=Example,C=US")
+                .conflicts_with_all(&["dc", "co", "st", "lo", "or", "ou", "cn", "sn", "em"])
+        )
         .arg(
             Arg::with_name("cn")
                 .short("N")
                 .long("common-name")
                 .value_name("common name")
-                .required(true)
+                .required_unless("subject")
         )
         .arg(
             Arg::with_name("st")
@@ -71,30 +168,476 @@ pub fn parser_name_builder<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
                 .value_name("country")
                 .required(false)
         )
+        .arg(
+            Arg::with_name("lo")
+                .short("L")
+                .long("locality")
+                .value_name("locality")
+        )
+        .arg(
+            Arg::with_name("ou")
+                .short("U")
+                .long("organisational-unit")
+                .value_name("unit")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Organisational unit, repeat for several in the order given")
+        )
+        .arg(
+            Arg::with_name("dc")
+                .long("domain-component")
+                .value_name("label")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Domain component, repeat from the top-level domain down, e.g. com then example")
+        )
+        .arg(
+            Arg::with_name("sn")
+                .long("subject-serial")
+                .value_name("serial")
+                .help("serialNumber attribute of the subject, unrelated to the certificate serial")
+        )
+        .arg(
+            Arg::with_name("em")
+                .long("email-address")
+                .value_name("email")
+                .help("emailAddress attribute of the subject, prefer --san-email")
+        )
+}
+
+pub fn parser_key_spec<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("algo")
+                .long("algo")
+                .value_name("algorithm")
+                .possible_values(&["rsa", "ec", "ed25519"])
+                .default_value("rsa")
+        )
+        .arg(
+            Arg::with_name("bits")
+                .long("bits")
+                .value_name("bits")
+                .possible_values(&["2048", "3072", "4096"])
+        )
+        .arg(
+            Arg::with_name("curve")
+                .long("curve")
+                .value_name("curve")
+                .possible_values(&["p256", "p384"])
+        )
+}
+
+pub fn parser_passphrase<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("passphrase_prompt")
+                .long("passphrase-prompt")
+                .help("Prompt for the private key passphrase")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("passphrase_env")
+                .long("passphrase-env")
+                .value_name("var")
+                .help("Read the private key passphrase from an environment variable")
+                .conflicts_with("passphrase_prompt")
+        )
+        .arg(
+            Arg::with_name("passphrase_file")
+                .long("passphrase-file")
+                .value_name("file")
+                .help("Read the private key passphrase from the first line of a file")
+                .conflicts_with_all(&["passphrase_prompt", "passphrase_env"])
+        )
+}
+
+pub fn parser_password<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("password_prompt")
+                .long("password-prompt")
+                .help("Prompt for the PKCS#12 password, the default")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("password_env")
+                .long("password-env")
+                .value_name("var")
+                .help("Read the PKCS#12 password from an environment variable")
+                .conflicts_with("password_prompt")
+        )
+        .arg(
+            Arg::with_name("password_file")
+                .long("password-file")
+                .value_name("file")
+                .help("Read the PKCS#12 password from the first line of a file")
+                .conflicts_with_all(&["password_prompt", "password_env"])
+        )
+}
+
+pub fn parser_cipher<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("cipher")
+                .long("cipher")
+                .value_name("cipher")
+                .help("Cipher used to encrypt the private key when a passphrase is given")
+                .possible_values(&["aes-128-cbc", "aes-192-cbc", "aes-256-cbc", "des-ede3-cbc"])
+                .default_value("aes-256-cbc")
+        )
 }
 
+/// Default `--before`, backdating notBefore to tolerate clock skew between hosts
+pub const DEFAULT_BACKDATE: &str = "-5m";
+
 pub fn parser_not_after_before<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
     app
         .arg(
             Arg::with_name("before")
                 .long("before")
                 .value_name("before")
-                .default_value("0")
+                .help("Start of the validity: days or a duration like -5m from now, an RFC 3339 or ASN.1 time")
+                .env("SIMPLECA_BACKDATE")
+                .default_value(DEFAULT_BACKDATE)
+                .allow_hyphen_values(true)
                 .required(true)
         )
         .arg(
             Arg::with_name("after")
                 .long("after")
                 .value_name("after")
+                .help("End of the validity: days or a duration like 12h from now, an RFC 3339 or ASN.1 time")
+                .allow_hyphen_values(true)
                 .default_value("3650")
                 .required(true)
         )
 }
 
+pub fn parser_pathlen<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("pathlen")
+                .long("pathlen")
+                .value_name("pathlen")
+                .help("Maximum number of intermediate CAs that may follow this one")
+        )
+}
+
+pub fn matches_pathlen(matches: &ArgMatches) -> Result<Option<u32>, ParseError> {
+    let pathlen = if let Some(x) = matches.value_of("pathlen") {
+        Some(x.parse::<u32>().map_err(|_| "invalid --pathlen")?)
+    } else {
+        None
+    };
+
+    Ok(pathlen)
+}
+
+pub fn parser_name_constraints<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("permit_name")
+                .long("permit-name")
+                .value_name("subtree")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only let the CA issue names of this type under DNS:example.com, DNS:.example.com \
+                       (subdomains only), IP:10.0.0.0/8, email:example.com or URI:.example.com")
+        )
+        .arg(
+            Arg::with_name("exclude_name")
+                .long("exclude-name")
+                .value_name("subtree")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Never let the CA issue names under this subtree, given as for --permit-name")
+        )
+}
+
+pub fn matches_name_constraints(matches: &ArgMatches) -> Result<Option<NameConstraints>, ParseError> {
+    let subtrees = |name: &str| -> Result<Vec<Subtree>, ParseError> {
+        matches.values_of(name).into_iter().flatten()
+            .map(|x| Subtree::parse(x).map_err(|err| format!("invalid --{}: {}", name.replace('_', "-"), err).into()))
+            .collect()
+    };
+
+    let constraints = NameConstraints {
+        permitted: subtrees("permit_name")?,
+        excluded: subtrees("exclude_name")?,
+    };
+
+    Ok(Some(constraints).filter(|x| !x.is_empty()))
+}
+
+pub fn parser_ca_dir<'a, 'b>(app: App<'a, 'b>, required: bool) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("ca_dir")
+                .long("ca-dir")
+                .value_name("dir")
+                .help("State directory of the CA")
+                .required(required)
+        )
+}
+
+pub fn parser_revocation_reason<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("reason")
+                .long("reason")
+                .value_name("reason")
+                .possible_values(&RevocationReason::names())
+                .case_insensitive(true)
+        )
+}
+
+pub fn parser_status<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("status")
+                .long("status")
+                .value_name("status")
+                .possible_values(&["valid", "revoked", "expired"])
+        )
+}
+
+pub fn matches_status(matches: &ArgMatches) -> Result<Option<Status>, ParseError> {
+    let status = match matches.value_of("status") {
+        Some("valid") => Some(Status::Valid),
+        Some("revoked") => Some(Status::Revoked),
+        Some("expired") => Some(Status::Expired),
+        Some(_) => return Err("invalid --status".into()),
+        None => None,
+    };
+
+    Ok(status)
+}
+
+pub fn parser_verify_options<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("purpose")
+                .long("purpose")
+                .value_name("purpose")
+                .possible_values(&["server", "client"])
+                .help("Check that the certificate may be used for TLS server or client authentication")
+        )
+        .arg(
+            Arg::with_name("host")
+                .long("host")
+                .value_name("name")
+                .help("Check that a DNS SubjectAltName matches the host name")
+        )
+        .arg(
+            Arg::with_name("ip")
+                .long("ip")
+                .value_name("address")
+                .help("Check that an IP SubjectAltName matches the address")
+        )
+}
+
+pub fn matches_verify_options(matches: &ArgMatches) -> Result<VerifyOptions, ParseError> {
+    let purpose = match matches.value_of("purpose") {
+        Some(x) => Some(Purpose::from_name(x).ok_or("invalid --purpose")?),
+        None => None,
+    };
+
+    let ip = match matches.value_of("ip") {
+        Some(x) => Some(x.parse::<IpAddr>().map_err(|_| format!("invalid IP address: {}", x))?),
+        None => None,
+    };
+
+    Ok(VerifyOptions {
+        purpose,
+        host: matches.value_of("host").map(|x| x.to_string()),
+        ip,
+    })
+}
+
+pub fn parser_crl_options<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("next_update")
+                .long("next-update")
+                .value_name("days")
+                .help("Days until the next CRL is due")
+                .default_value("30")
+        )
+}
+
+pub fn parser_inform<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("inform")
+                .long("inform")
+                .value_name("format")
+                .help("Format of the input files, told apart by their contents by default")
+                .possible_values(&["auto", "pem", "der"])
+                .default_value("auto")
+        )
+}
+
+pub fn parser_outform<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("outform")
+                .long("outform")
+                .value_name("format")
+                .help("Format of the output files")
+                .possible_values(&["pem", "der"])
+                .default_value("pem")
+        )
+}
+
+fn matches_format(matches: &ArgMatches, name: &str) -> Result<Format, ParseError> {
+    let format = match matches.value_of(name).unwrap_or("auto") {
+        "auto" => Format::Auto,
+        "pem" => Format::Pem,
+        "der" => Format::Der,
+        _ => return Err(format!("invalid --{}", name).into()),
+    };
+
+    Ok(format)
+}
+
+pub fn matches_inform(matches: &ArgMatches) -> Result<Format, ParseError> {
+    matches_format(matches, "inform")
+}
+
+pub fn matches_outform(matches: &ArgMatches) -> Result<Format, ParseError> {
+    matches_format(matches, "outform")
+}
+
+pub fn parser_overwrite<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .help("Replace output files that already exist")
+        )
+        .arg(
+            Arg::with_name("backup")
+                .long("backup")
+                .help("Replace output files that already exist, renaming each to <file>.bak first")
+        )
+}
+
+pub fn matches_overwrite(matches: &ArgMatches) -> Overwrite {
+    if matches.is_present("backup") {
+        Overwrite::Backup
+    } else if matches.is_present("force") {
+        Overwrite::Replace
+    } else {
+        Overwrite::Refuse
+    }
+}
+
+/// Parse a serial number in hex, optionally prefixed with `0x` or split by colons
+pub fn parse_serial(x: &str) -> Result<BigNum, ParseError> {
+    let hex = x.trim_start_matches("0x").replace(':', "");
+
+    if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("invalid serial number: {}", x).into());
+    }
+
+    Ok(BigNum::from_hex_str(&hex)?)
+}
+
+pub fn matches_revocation_reason(matches: &ArgMatches) -> Result<Option<RevocationReason>, ParseError> {
+    let reason = if let Some(x) = matches.value_of("reason") {
+        Some(RevocationReason::from_name(x).ok_or("invalid --reason")?)
+    } else {
+        None
+    };
+
+    Ok(reason)
+}
+
+pub fn matches_crl_options(matches: &ArgMatches) -> Result<Asn1Time, ParseError> {
+    let days = matches.value_of("next_update").unwrap_or("30").parse::<u32>().map_err(|_| "invalid --next-update")?;
+
+    Ok(Asn1Time::days_from_now(days)?)
+}
+
 pub enum CsrExt {
     Client,
     Server,
-    SanDns(Vec<String>),
+    CodeSigning,
+    EmailProtection,
+    OcspSigning,
+    /// An extended key usage given by its dotted OID
+    Eku(String),
+    San(Vec<SanEntry>),
+}
+
+/// An entry of the subjectAltName extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SanEntry {
+    Dns(String),
+    Ip(IpAddr),
+    Email(String),
+    Uri(String),
+    /// Microsoft user principal name, encoded as an otherName
+    Upn(String),
+}
+
+/// OID of the otherName carrying a user principal name
+pub const UPN_OID: &str = "1.3.6.1.4.1.311.20.2.3";
+
+fn is_dns_name(x: &str) -> bool {
+    let x = x.strip_prefix("*.").unwrap_or(x);
+
+    !x.is_empty() && x.len() <= 253 && x.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+fn is_mailbox(x: &str, ascii: bool) -> bool {
+    match x.rsplit_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !local.chars().any(|c| c.is_whitespace() || c.is_control() || c == '@')
+                && (!ascii || local.is_ascii())
+                && is_dns_name(domain)
+                && !domain.starts_with("*.")
+        }
+        None => false,
+    }
+}
+
+fn is_uri(x: &str) -> bool {
+    match x.split_once(':') {
+        Some((scheme, rest)) => {
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+                && !rest.is_empty()
+                && x.chars().all(|c| c.is_ascii_graphic())
+        }
+        None => false,
+    }
+}
+
+impl SanEntry {
+    /// Parse and validate a value given to one of the `--san-*` options
+    pub fn parse(kind: &str, value: &str) -> Result<SanEntry, ParseError> {
+        let invalid = || ParseError::San(format!("invalid {} subjectAltName: {}", kind, value));
+
+        let entry = match kind {
+            "dns" if is_dns_name(value) => SanEntry::Dns(value.to_string()),
+            "ip" => SanEntry::Ip(value.parse::<IpAddr>().map_err(|_| invalid())?),
+            "email" if is_mailbox(value, true) => SanEntry::Email(value.to_string()),
+            "uri" if is_uri(value) => SanEntry::Uri(value.to_string()),
+            "upn" if is_mailbox(value, false) => SanEntry::Upn(value.to_string()),
+            _ => return Err(invalid()),
+        };
+
+        Ok(entry)
+    }
 }
 
 pub fn parser_csr_extensions<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
@@ -102,16 +645,41 @@ pub fn parser_csr_extensions<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
         .arg(
             Arg::with_name("ext_server")
                 .long("ext-server")
-                .value_name("Enable the server extensions")
+                .help("Enable the server extensions (serverAuth)")
                 .takes_value(false)
         )
         .arg(
             Arg::with_name("ext_client")
                 .long("ext-client")
-                .conflicts_with("ext_server")
-                .value_name("Enable the client extensions")
+                .help("Enable the client extensions (clientAuth)")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("ext_code_signing")
+                .long("ext-code-signing")
+                .help("Enable the code signing extensions (codeSigning)")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("ext_email_protection")
+                .long("ext-email-protection")
+                .help("Enable the S/MIME extensions (emailProtection)")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("ext_ocsp_signing")
+                .long("ext-ocsp-signing")
+                .help("Enable the OCSP responder extensions (OCSPSigning)")
                 .takes_value(false)
         )
+        .arg(
+            Arg::with_name("ekus")
+                .long("eku")
+                .value_name("oid")
+                .help("Add an extended key usage by its dotted OID")
+                .number_of_values(1)
+                .multiple(true)
+        )
         .arg(
             Arg::with_name("san_dnss")
                 .long("san-dns")
@@ -119,44 +687,319 @@ pub fn parser_csr_extensions<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
                 .number_of_values(1)
                 .multiple(true)
         )
+        .arg(
+            Arg::with_name("san_ips")
+                .long("san-ip")
+                .value_name("Specify SubjectAltName IPv4 or IPv6 addresses")
+                .number_of_values(1)
+                .multiple(true)
+        )
+        .arg(
+            Arg::with_name("san_emails")
+                .long("san-email")
+                .value_name("Specify SubjectAltName email addresses")
+                .number_of_values(1)
+                .multiple(true)
+        )
+        .arg(
+            Arg::with_name("san_uris")
+                .long("san-uri")
+                .value_name("Specify SubjectAltName URIs")
+                .number_of_values(1)
+                .multiple(true)
+        )
+        .arg(
+            Arg::with_name("san_upns")
+                .long("san-upn")
+                .value_name("Specify SubjectAltName user principal names")
+                .number_of_values(1)
+                .multiple(true)
+        )
+}
+
+/// Split `x` on the `separator`s not escaped by a backslash
+fn split_unescaped(x: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+
+    for (i, c) in x.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == separator {
+            parts.push(&x[start..i]);
+            start = i + 1;
+        }
+    }
+
+    parts.push(&x[start..]);
+    parts
+}
+
+/// Resolve `\c` escapes and the `\XX` hex pairs of RFC 4514, which may spell out UTF-8 bytes
+fn unescape(x: &str) -> Result<String, ParseError> {
+    let mut bytes = Vec::with_capacity(x.len());
+    let mut rest = x.as_bytes();
+
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+
+        if b != b'\\' {
+            bytes.push(b);
+            continue;
+        }
+
+        let hex = rest.get(..2)
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+
+        match (hex, rest.split_first()) {
+            (Some(x), _) => {
+                bytes.push(x);
+                rest = &rest[2..];
+            }
+            (None, Some((&c, tail))) => {
+                bytes.push(c);
+                rest = tail;
+            }
+            (None, None) => return Err(ParseError::Name(format!("invalid --subject: {} ends with a backslash", x))),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| ParseError::Name(format!("invalid --subject: {} is not valid UTF-8", x)))
+}
+
+/// Parse a subject in the `/C=US/O=Example/CN=host` form of OpenSSL or the RFC 4514
+/// `CN=host,O=Example,C=US` form into its RDNs, most significant first.
+///
+/// `+` joins the attributes of a multi-valued RDN, and a backslash escapes separators.
+pub fn parse_subject(x: &str) -> Result<Vec<Rdn>, ParseError> {
+    // RFC 4514 lists the most significant RDN last
+    let (body, separator, reverse) = match x.strip_prefix('/') {
+        Some(body) => (body, '/', false),
+        None => (x, ',', true),
+    };
+
+    let mut rdns = Vec::new();
+
+    for rdn in split_unescaped(body, separator) {
+        let mut attributes = Rdn::new();
+
+        for attribute in split_unescaped(rdn, '+') {
+            let (field, value) = match attribute.find('=') {
+                Some(i) => (attribute[..i].trim(), &attribute[i + 1..]),
+                None => return Err(ParseError::Name(format!("invalid --subject: expected type=value, got {:?}", attribute))),
+            };
+
+            let known = !field.is_empty()
+                && field.chars().all(|c| c.is_ascii_alphanumeric() || c == '.')
+                && Asn1Object::from_str(field).is_ok();
+
+            if !known {
+                return Err(ParseError::Name(format!("invalid --subject: unknown attribute type {:?}", field)));
+            }
+
+            let value = unescape(value)?;
+
+            if value.is_empty() {
+                return Err(ParseError::Name(format!("invalid --subject: {} is empty", field)));
+            }
+
+            attributes.push((field.to_string(), value));
+        }
+
+        rdns.push(attributes);
+    }
+
+    if reverse {
+        rdns.reverse();
+    }
+
+    Ok(rdns)
+}
+
+/// Upper bound of RFC 5280 on the length of an attribute type, in characters
+fn name_upper_bound(nid: Nid) -> Option<usize> {
+    match nid {
+        Nid::COMMONNAME | Nid::ORGANIZATIONNAME | Nid::ORGANIZATIONALUNITNAME | Nid::TITLE | Nid::SERIALNUMBER => Some(64),
+        Nid::LOCALITYNAME | Nid::STATEORPROVINCENAME | Nid::PSEUDONYM => Some(128),
+        Nid::PKCS9_EMAILADDRESS => Some(255),
+        Nid::GIVENNAME | Nid::SURNAME | Nid::INITIALS | Nid::GENERATIONQUALIFIER | Nid::NAME => Some(32768),
+        _ => None,
+    }
+}
+
+/// Check the subject attributes against the length bounds and character sets of their types, so
+/// OpenSSL is only handed values it encodes as they are
+pub fn validate_name(rdns: &[Rdn]) -> Result<(), ParseError> {
+    for (field, value) in rdns.iter().flatten() {
+        if field.contains('\0') {
+            return Err(NameError::InvalidType(field.clone()).into());
+        }
+
+        let nid = Asn1Object::from_str(field)?.nid();
+        let short_name = nid.short_name()?;
+
+        if value.is_empty() {
+            return Err(ParseError::NameInvalid(short_name, "must not be empty"));
+        }
+
+        match nid {
+            Nid::COUNTRYNAME if value.len() != 2 || !value.bytes().all(|x| x.is_ascii_uppercase()) => {
+                return Err(ParseError::NameInvalid(short_name, "must be a two-letter ISO 3166 code in capitals, e.g. GB"));
+            }
+            // PrintableString
+            Nid::SERIALNUMBER if !value.chars().all(|x| x.is_ascii_alphanumeric() || " '()+,-./:=?".contains(x)) => {
+                return Err(ParseError::NameInvalid(short_name, "may only hold letters, digits, spaces and '()+,-./:=?"));
+            }
+            // IA5String
+            Nid::DOMAINCOMPONENT | Nid::PKCS9_EMAILADDRESS if !value.is_ascii() => {
+                return Err(ParseError::NameInvalid(short_name, "may only hold ASCII characters"));
+            }
+            _ => {}
+        }
+
+        match name_upper_bound(nid) {
+            Some(max) if value.chars().count() > max => return Err(ParseError::NameTooLong(short_name, max)),
+            _ => {}
+        }
+    }
+
+    Ok(())
 }
 
 pub fn matches_name_builder(matches: &ArgMatches) -> Result<X509Name, ParseError> {
-    let mut x509_name = X509NameBuilder::new()?;
+    let rdns = match matches.value_of("subject") {
+        Some(subject) => parse_subject(subject)?,
+        None => {
+            if !matches.is_present("cn") {
+                return Err(ParseError::Name("missing --common-name".to_string()));
+            }
+
+            let mut rdns = Vec::new();
+
+            for (arg, field) in NAME_ATTRIBUTES {
+                for x in matches.values_of(arg).into_iter().flatten() {
+                    rdns.push(vec![(field.to_string(), x.to_string())]);
+                }
+            }
 
-    let cn = matches.value_of("cn").ok_or("common name")?;
+            rdns
+        }
+    };
+
+    validate_name(&rdns)?;
+
+    Ok(build_name(&rdns)?)
+}
 
-    x509_name.append_entry_by_text("CN", cn)?;
+pub fn matches_key_spec(matches: &ArgMatches) -> Result<KeySpec, ParseError> {
+    let algo = matches.value_of("algo").unwrap_or("rsa");
 
-    if let Some(x) = matches.value_of("st") {
-        x509_name.append_entry_by_text("ST", x)?;
+    if algo != "rsa" && matches.is_present("bits") {
+        return Err("--bits is only valid with --algo rsa".into());
     }
 
-    if let Some(x) = matches.value_of("or") {
-        x509_name.append_entry_by_text("O", x)?;
+    if algo != "ec" && matches.is_present("curve") {
+        return Err("--curve is only valid with --algo ec".into());
     }
 
-    if let Some(x) = matches.value_of("co") {
-        x509_name.append_entry_by_text("C", x)?;
+    let spec = match algo {
+        "rsa" => KeySpec::Rsa(matches.value_of("bits").unwrap_or("2048").parse::<u32>().map_err(|_| "invalid --bits")?),
+        "ec" => KeySpec::Ec(match matches.value_of("curve").unwrap_or("p256") {
+            "p256" => EcCurve::P256,
+            "p384" => EcCurve::P384,
+            _ => return Err("invalid --curve".into()),
+        }),
+        "ed25519" => KeySpec::Ed25519,
+        _ => return Err("invalid --algo".into()),
+    };
+
+    Ok(spec)
+}
+
+/// Read a secret from the `<name>_file`, `<name>_env` or `<name>_prompt` argument, prompting
+/// without any of them when `prompt_by_default` is set
+fn matches_secret(
+    matches: &ArgMatches,
+    name: &str,
+    confirm: bool,
+    prompt_by_default: bool,
+) -> Result<Option<Vec<u8>>, ParseError> {
+    let secret = if let Some(x) = matches.value_of(format!("{}_file", name)) {
+        let mut contents = Vec::<u8>::default();
+        File::open(x)?.read_to_end(&mut contents)?;
+
+        let line = contents.split(|c| *c == b'\n').next().unwrap_or_default();
+        strip_newline(line).to_vec()
+    } else if let Some(x) = matches.value_of(format!("{}_env", name)) {
+        env::var(x).map_err(|_| format!("environment variable {} is not set", x))?.into_bytes()
+    } else if prompt_by_default || matches.is_present(format!("{}_prompt", name)) {
+        let secret = prompt_passphrase(&format!("Enter {}: ", name))?;
+
+        if confirm && prompt_passphrase(&format!("Verifying - Enter {}: ", name))? != secret {
+            return Err(format!("{}s do not match", name).into());
+        }
+
+        secret
+    } else {
+        return Ok(None);
+    };
+
+    if secret.is_empty() {
+        return Err(format!("empty {}", name).into());
     }
 
-    Ok(x509_name.build())
+    Ok(Some(secret))
 }
 
-pub fn matches_not_after_before(matches: &ArgMatches) -> Result<(Option<Asn1Time>, Option<Asn1Time>), ParseError> {
+pub fn matches_passphrase(matches: &ArgMatches, confirm: bool) -> Result<Option<Vec<u8>>, ParseError> {
+    matches_secret(matches, "passphrase", confirm, false)
+}
+
+/// Password of a PKCS#12 bundle, prompting for it when no other source is given
+pub fn matches_password(matches: &ArgMatches, confirm: bool) -> Result<String, ParseError> {
+    let password = matches_secret(matches, "password", confirm, true)?.unwrap_or_default();
+
+    Ok(String::from_utf8(password).map_err(|_| "password is not valid UTF-8")?)
+}
+
+pub fn matches_cipher(matches: &ArgMatches) -> Result<Cipher, ParseError> {
+    let cipher = match matches.value_of("cipher").unwrap_or("aes-256-cbc") {
+        "aes-128-cbc" => Cipher::aes_128_cbc(),
+        "aes-192-cbc" => Cipher::aes_192_cbc(),
+        "aes-256-cbc" => Cipher::aes_256_cbc(),
+        "des-ede3-cbc" => Cipher::des_ede3_cbc(),
+        _ => return Err("invalid --cipher".into()),
+    };
+
+    Ok(cipher)
+}
+
+pub fn matches_not_after_before(matches: &ArgMatches) -> Result<Validity, ParseError> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs() as i64).unwrap_or_default();
+
     let before = if let Some(x) = matches.value_of("before") {
-        Some(Asn1Time::days_from_now(x.parse::<u32>().map_err(|_| "before")?)?)
+        Some(Asn1Time::from_unix(parse_time(x, now).ok_or("invalid --before")?)?)
     } else {
         None
     };
 
     let after = if let Some(x) = matches.value_of("after") {
-        Some(Asn1Time::days_from_now(x.parse::<u32>().map_err(|_| "after")?)?)
+        Some(Asn1Time::from_unix(parse_time(x, now).ok_or("invalid --after")?)?)
     } else {
         None
     };
 
-    Ok((before, after))
+    let not_before_after = (before, after);
+
+    if !validity_is_ordered(&not_before_after)? {
+        return Err("--before must be earlier than --after".into());
+    }
+
+    Ok(not_before_after)
 }
 
 pub fn matches_csr_extensions(matches: &ArgMatches) -> Result<Vec<CsrExt>, ParseError> {
@@ -170,52 +1013,121 @@ pub fn matches_csr_extensions(matches: &ArgMatches) -> Result<Vec<CsrExt>, Parse
         res.push(CsrExt::Client);
     }
 
-    if let Some(sans) = matches.values_of("san_dnss") {
-        let mut r = Vec::<String>::default();
-        for san in sans {
-            r.push(san.to_string());
+    if matches.is_present("ext_code_signing") {
+        res.push(CsrExt::CodeSigning);
+    }
+
+    if matches.is_present("ext_email_protection") {
+        res.push(CsrExt::EmailProtection);
+    }
+
+    if matches.is_present("ext_ocsp_signing") {
+        res.push(CsrExt::OcspSigning);
+    }
+
+    if let Some(ekus) = matches.values_of("ekus") {
+        for eku in ekus {
+            let dotted = eku.split('.').all(|x| !x.is_empty() && x.chars().all(|c| c.is_ascii_digit()));
+
+            if !dotted || Asn1Object::from_str(eku).is_err() {
+                return Err(format!("invalid extended key usage OID: {}", eku).into());
+            }
+
+            res.push(CsrExt::Eku(eku.to_string()));
         }
+    }
+
+    let mut r = Vec::<SanEntry>::default();
+
+    for (arg, kind) in &[("san_dnss", "dns"), ("san_ips", "ip"), ("san_emails", "email"), ("san_uris", "uri"), ("san_upns", "upn")] {
+        if let Some(sans) = matches.values_of(arg) {
+            for san in sans {
+                r.push(SanEntry::parse(kind, san)?);
+            }
+        }
+    }
 
-        res.push(CsrExt::SanDns(r))
+    if !r.is_empty() {
+        res.push(CsrExt::San(r))
     }
 
     Ok(res)
 }
 
-pub fn run_csr_extensions(exts: &Vec<CsrExt>, extensions: &mut Stack<X509Extension>, req_builder: &X509ReqBuilder)
+/// Add the requested extensions to `extensions`.
+///
+/// All key purposes are combined into a single ExtendedKeyUsage extension, together with
+/// a critical KeyUsage extension carrying the bits each of those purposes needs.
+/// keyEncipherment is only requested for RSA keys (`key`), the only type that can encrypt.
+pub fn run_csr_extensions(exts: &[CsrExt], key: Id, extensions: &mut Stack<X509Extension>, req_builder: &X509ReqBuilder)
     -> Result<(), SslError> {
+    let mut key_usage = KeyUsage::new();
+    let mut ext_key_usage = ExtendedKeyUsage::new();
+    let mut purposes = 0;
+
     for ext in exts {
         match ext {
             CsrExt::Client => {
-                extensions.push(
-                    X509Extension::new_nid(None, None, Nid::NETSCAPE_CERT_TYPE, "SSL Client")?
-                )?;
-
-                extensions.push(
-                    X509Extension::new_nid(None, None, Nid::NETSCAPE_COMMENT, "Client Certificate")?
-                )?;
+                ext_key_usage.client_auth();
+                key_usage.digital_signature();
             }
             CsrExt::Server => {
-                extensions.push(
-                    X509Extension::new_nid(None, None, Nid::NETSCAPE_CERT_TYPE, "SSL Server")?
-                )?;
-
-                extensions.push(
-                    X509Extension::new_nid(None, None, Nid::NETSCAPE_COMMENT, "Server Certificate")?
-                )?;
+                ext_key_usage.server_auth();
+                key_usage.digital_signature();
+                if key == Id::RSA {
+                    key_usage.key_encipherment();
+                }
+            }
+            CsrExt::CodeSigning => {
+                ext_key_usage.code_signing();
+                key_usage.digital_signature();
             }
-            CsrExt::SanDns(dnss) => {
+            CsrExt::EmailProtection => {
+                ext_key_usage.email_protection();
+                key_usage.digital_signature().non_repudiation();
+                if key == Id::RSA {
+                    key_usage.key_encipherment();
+                }
+            }
+            CsrExt::OcspSigning => {
+                ext_key_usage.other("OCSPSigning");
+                key_usage.digital_signature().non_repudiation();
+            }
+            CsrExt::Eku(oid) => {
+                ext_key_usage.other(oid);
+                key_usage.digital_signature();
+            }
+            CsrExt::San(sans) => {
                 let mut subject_alt_name = SubjectAlternativeName::new();
 
-                for name in dnss {
-                    subject_alt_name.dns(&name);
+                for san in sans {
+                    match san {
+                        SanEntry::Dns(x) => subject_alt_name.dns(x),
+                        SanEntry::Ip(x) => subject_alt_name.ip(&x.to_string()),
+                        SanEntry::Email(x) => subject_alt_name.email(x),
+                        SanEntry::Uri(x) => subject_alt_name.uri(x),
+                        SanEntry::Upn(x) => subject_alt_name.other_name2(
+                            Asn1Object::from_str(UPN_OID)?,
+                            &der::tlv(der::TAG_UTF8_STRING, x.as_bytes()),
+                        ),
+                    };
                 }
 
                 let subject_alt_name = subject_alt_name.build(&req_builder.x509v3_context(None))?;
 
                 extensions.push(subject_alt_name)?;
+
+                continue;
             }
         }
+
+        purposes += 1;
+    }
+
+    if purposes > 0 {
+        extensions.push(key_usage.critical().build()?)?;
+        extensions.push(ext_key_usage.build()?)?;
     }
+
     Ok(())
-}
\ No newline at end of file
+}
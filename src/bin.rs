@@ -1,11 +1,215 @@
-use clap::App;
+use clap::{App, ArgMatches, ErrorKind as ClapErrorKind};
 use clap::SubCommand;
 use clap::Arg;
 use simpleca::*;
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 use simpleca::args::*;
 use openssl::stack::Stack;
-use openssl::x509::X509Extension;
+use openssl::x509::{X509Extension, X509Ref};
+use openssl::pkey::{PKey, Private};
+use simpleca::db::CaDatabase;
+use simpleca::output::{create_private_dir, open_permissions, Access, OutputFile, Overwrite};
+use simpleca::policy::Policy;
+use simpleca::inspect::{describe, read_object};
+use std::io::{ErrorKind, Read, Write};
+use simpleca::verify::verify_chain;
+use simpleca::time::asn1_to_rfc3339;
+use openssl::asn1::Asn1Time;
+use openssl::nid::Nid;
+use std::path::Path;
+use std::error::Error;
+use std::fmt;
+
+/// Failure of a command, with the file or step it concerns
+#[derive(Debug)]
+enum CliError {
+    /// An option has an invalid value
+    Args(ParseError),
+    /// An input file could not be opened or decoded
+    Read(String, LoadError),
+    /// An output file could not be created or written
+    Write(String, LoadError),
+    /// The database or configuration in a CA state directory could not be used
+    CaDir(String, LoadError),
+    /// The password of a PKCS#12 bundle is wrong
+    WrongPassword(String),
+    /// A private key does not belong to the certificate it goes with
+    KeyMismatch(String, String),
+    /// The CA refused to sign a request
+    Sign(String, SignError),
+    /// A certificate did not verify
+    Verify(String, Vec<String>),
+    /// An OpenSSL operation on data already loaded failed
+    Step(&'static str, LoadError),
+    /// The command cannot do what was asked
+    Failed(String),
+}
+
+impl CliError {
+    /// Process exit code, distinct for each kind of failure
+    fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Failed(_) | CliError::Step(..) => 1,
+            CliError::Args(_) => 2,
+            CliError::Read(_, LoadError::PassphraseRequired) |
+            CliError::Read(_, LoadError::WrongPassphrase) |
+            CliError::WrongPassword(_) |
+            CliError::KeyMismatch(..) => 5,
+            CliError::Read(..) => 3,
+            CliError::Write(..) => 4,
+            CliError::CaDir(..) => 6,
+            CliError::Sign(..) => 7,
+            CliError::Verify(..) => 8,
+        }
+    }
+}
+
+/// An error followed by its sources, e.g. `not a valid certificate: error:0480006C:PEM routines...`
+fn with_sources(err: &dyn Error) -> String {
+    let mut msg = err.to_string();
+    let mut source = err.source();
+
+    while let Some(x) = source {
+        msg.push_str(": ");
+        msg.push_str(&x.to_string());
+        source = x.source();
+    }
+
+    msg
+}
+
+fn fmt_load_error(f: &mut fmt::Formatter, path: &str, err: &LoadError, writing: bool) -> fmt::Result {
+    match err {
+        LoadError::IO(err) if err.kind() == ErrorKind::NotFound && !writing => write!(f, "{}: no such file", path),
+        LoadError::IO(err) if err.kind() == ErrorKind::AlreadyExists => write!(f, "{}: already exists, pass --force or --backup to replace it", path),
+        LoadError::IO(err) => write!(f, "{}: {}", path, err),
+        LoadError::PassphraseRequired => write!(f, "{}: {}, use --passphrase-prompt, --passphrase-env or --passphrase-file", path, err),
+        _ => write!(f, "{}: {}", path, with_sources(err)),
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Args(ParseError::Arg(err)) => write!(f, "{}", err),
+            CliError::Args(err) => write!(f, "{}", with_sources(err)),
+            CliError::Read(path, err) | CliError::CaDir(path, err) => fmt_load_error(f, path, err, false),
+            CliError::Write(path, err) => fmt_load_error(f, path, err, true),
+            CliError::WrongPassword(path) => write!(f, "{}: wrong password", path),
+            CliError::KeyMismatch(pkey, cert) => write!(f, "{}: the private key does not belong to {}", pkey, cert),
+            CliError::Sign(path, SignError::KeyMismatch) => write!(f, "{}: {}, pass --override-pubkey to certify --pubkey anyway", path, SignError::KeyMismatch),
+            CliError::Sign(path, SignError::Policy(violations)) => {
+                write!(f, "{}: the request violates the CA policy:", path)?;
+
+                for violation in violations {
+                    write!(f, "\n  {}", violation)?;
+                }
+
+                Ok(())
+            }
+            CliError::Sign(path, SignError::NameConstraints(violations)) => {
+                write!(f, "{}: the request is outside the name constraints of the CA:", path)?;
+
+                for violation in violations {
+                    write!(f, "\n  {}", violation)?;
+                }
+
+                Ok(())
+            }
+            CliError::Sign(path, err) => write!(f, "{}: {}", path, with_sources(err)),
+            CliError::Verify(path, errors) => {
+                let lines = errors.iter().map(|x| format!("{}: {}", path, x)).collect::<Vec<_>>();
+                write!(f, "{}", lines.join("\n"))
+            }
+            CliError::Step(step, err) => fmt_load_error(f, step, err, true),
+            CliError::Failed(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Error for CliError {}
+
+impl From<ParseError> for CliError {
+    fn from(x: ParseError) -> Self {
+        CliError::Args(x)
+    }
+}
+
+/// Attach the file or step an error comes from
+trait Context<T> {
+    fn reading<P: AsRef<Path>>(self, path: P) -> Result<T, CliError>;
+    fn writing<P: AsRef<Path>>(self, path: P) -> Result<T, CliError>;
+    fn ca_dir<P: AsRef<Path>>(self, path: P) -> Result<T, CliError>;
+    fn step(self, step: &'static str) -> Result<T, CliError>;
+}
+
+impl<T, E: Into<LoadError>> Context<T> for Result<T, E> {
+    fn reading<P: AsRef<Path>>(self, path: P) -> Result<T, CliError> {
+        self.map_err(|x| CliError::Read(path.as_ref().display().to_string(), x.into()))
+    }
+
+    fn writing<P: AsRef<Path>>(self, path: P) -> Result<T, CliError> {
+        self.map_err(|x| CliError::Write(path.as_ref().display().to_string(), x.into()))
+    }
+
+    fn ca_dir<P: AsRef<Path>>(self, path: P) -> Result<T, CliError> {
+        self.map_err(|x| CliError::CaDir(path.as_ref().display().to_string(), x.into()))
+    }
+
+    fn step(self, step: &'static str) -> Result<T, CliError> {
+        self.map_err(|x| CliError::Step(step, x.into()))
+    }
+}
+
+fn open_input<P: AsRef<Path>>(path: P) -> Result<File, CliError> {
+    OpenOptions::new().read(true).open(&path).reading(path)
+}
+
+/// Start writing an output file, which only appears at its path once committed
+fn create_output<P: AsRef<Path>>(path: P, overwrite: Overwrite) -> Result<OutputFile, CliError> {
+    OutputFile::create(&path, overwrite, Access::Shared).writing(path)
+}
+
+/// Start writing an output file holding a private key, which only its owner may read
+fn create_key_output<P: AsRef<Path>>(path: P, overwrite: Overwrite) -> Result<OutputFile, CliError> {
+    OutputFile::create(&path, overwrite, Access::Owner).writing(path)
+}
+
+fn commit_output(file: OutputFile) -> Result<(), CliError> {
+    let path = file.path().to_path_buf();
+    file.commit().writing(path)
+}
+
+fn load_pkey(path: &str, passphrase: Option<&[u8]>, format: Format) -> Result<PKey<Private>, CliError> {
+    pkey_from_file(&mut open_input(path)?, passphrase, format).reading(path)
+}
+
+/// Load the private key of a CA, warning when other users have access to the file
+fn load_ca_pkey(path: &str, passphrase: Option<&[u8]>, format: Format) -> Result<PKey<Private>, CliError> {
+    let pkey = load_pkey(path, passphrase, format)?;
+
+    if open_permissions(path).reading(path)? != 0 {
+        eprintln!("warning: {}: other users have access to the ca private key, restrict it with chmod 600", path);
+    }
+
+    Ok(pkey)
+}
+
+fn signed<T>(path: &str, res: Result<T, SignError>) -> Result<T, CliError> {
+    res.map_err(|x| CliError::Sign(path.to_string(), x))
+}
+
+/// Validity given on the command line, with notAfter clamped to that of the issuing CA
+fn issued_validity(matches: &ArgMatches, ca_cert: &X509Ref, path: &str) -> Result<Validity, CliError> {
+    let mut not_a_b = matches_not_after_before(matches)?;
+
+    if clamp_not_after(ca_cert, &mut not_a_b).step("clamping the validity")? {
+        let not_after = asn1_to_rfc3339(ca_cert.not_after()).step("reading the ca validity")?;
+        eprintln!("warning: the validity ends after that of {}, shortening it to {}", path, not_after);
+    }
+
+    Ok(not_a_b)
+}
 
 fn main() {
     let matches = App::new("Simplistic self-signed CA generator")
@@ -15,31 +219,39 @@ fn main() {
             SubCommand::with_name("key")
                 .about("generates a private key in PEM format")
                 .subcommand(
-                    SubCommand::with_name("gen")
-                        .about("generate a private key or a public key")
-                        .arg(
-                            Arg::with_name("output")
-                                .required(true)
-                                .index(1)
+                    parser_overwrite(parser_outform(parser_cipher(
+                        parser_passphrase(
+                            parser_key_spec(
+                                SubCommand::with_name("gen")
+                                    .about("generate a private key or a public key")
+                                    .arg(
+                                        Arg::with_name("output")
+                                            .required(true)
+                                            .index(1)
+                                    )
+                            )
                         )
+                    )))
                 )
                 .subcommand(
-                    SubCommand::with_name("pub")
-                        .about("generates a public key from private key in PEM format")
-                        .arg(
-                            Arg::with_name("pkey")
-                                .required(true)
-                                .index(1)
-                        )
-                        .arg(
-                            Arg::with_name("output")
-                                .required(true)
-                                .index(2)
-                        )
+                    parser_overwrite(parser_inform(parser_outform(parser_passphrase(
+                        SubCommand::with_name("pub")
+                            .about("generates a public key from private key in PEM format")
+                            .arg(
+                                Arg::with_name("pkey")
+                                    .required(true)
+                                    .index(1)
+                            )
+                            .arg(
+                                Arg::with_name("output")
+                                    .required(true)
+                                    .index(2)
+                            )
+                    ))))
                 )
         )
         .subcommand(
-            parser_not_after_before(
+            parser_overwrite(parser_inform(parser_outform(parser_passphrase(parser_not_after_before(parser_name_constraints(
                 parser_name_builder(
                     SubCommand::with_name("ca")
                         .about("generates a ca certificate from a given private key in PEM format")
@@ -54,12 +266,12 @@ fn main() {
                                 .index(2)
                         )
                 )
-            )
+            ))))))
         )
         .subcommand(
-            parser_not_after_before(
+            parser_overwrite(parser_inform(parser_outform(parser_ca_dir(parser_passphrase(parser_not_after_before(
                 SubCommand::with_name("sign")
-                    .about("generates a ca certificate from a given private key in PEM format")
+                    .about("signs a certificate signing request with a ca certificate and private key")
                     .arg(
                         Arg::with_name("cert")
                             .required(true)
@@ -71,26 +283,261 @@ fn main() {
                             .index(2)
                     )
                     .arg(
-                        Arg::with_name("pubkey")
+                        Arg::with_name("csr")
                             .required(true)
                             .index(3)
                     )
-
                     .arg(
-                        Arg::with_name("csr")
+                        Arg::with_name("output")
                             .required(true)
                             .index(4)
                     )
+                    .arg(
+                        Arg::with_name("pubkey")
+                            .long("pubkey")
+                            .value_name("pubkey")
+                            .help("Public key expected in the request")
+                    )
+                    .arg(
+                        Arg::with_name("override_pubkey")
+                            .long("override-pubkey")
+                            .requires("pubkey")
+                            .help("Certify --pubkey even if it differs from the key in the request")
+                    )
+                    .arg(
+                        Arg::with_name("fullchain")
+                            .long("fullchain")
+                            .value_name("file")
+                            .help("Also write the certificate followed by the ca chain")
+                    )
+                    .arg(
+                        Arg::with_name("chain")
+                            .long("chain")
+                            .value_name("file")
+                            .help("Also write the ca chain")
+                    )
+                    .arg(
+                        Arg::with_name("bundle")
+                            .long("bundle")
+                            .value_name("file")
+                            .requires("bundle_key")
+                            .help("Also write the private key of the certificate, the certificate and the ca chain")
+                    )
+                    .arg(
+                        Arg::with_name("bundle_key")
+                            .long("bundle-key")
+                            .value_name("file")
+                            .requires("bundle")
+                            .help("Unencrypted private key of the certificate for --bundle")
+                    )
+            )), false))))
+        )
+        .subcommand(
+            parser_inform(parser_revocation_reason(parser_ca_dir(
+                SubCommand::with_name("revoke")
+                    .about("marks a certificate as revoked in the ca database")
+                    .arg(
+                        Arg::with_name("target")
+                            .value_name("serial|cert")
+                            .required(true)
+                            .index(1)
+                    ),
+                true,
+            )))
+        )
+        .subcommand(
+            parser_overwrite(parser_inform(parser_outform(parser_crl_options(parser_passphrase(parser_ca_dir(
+                SubCommand::with_name("crl")
+                    .about("generates a certificate revocation list from the ca database")
+                    .arg(
+                        Arg::with_name("cert")
+                            .required(true)
+                            .index(1)
+                    )
+                    .arg(
+                        Arg::with_name("pkey")
+                            .required(true)
+                            .index(2)
+                    )
                     .arg(
                         Arg::with_name("output")
                             .required(true)
-                            .index(5)
+                            .index(3)
+                    ),
+                true,
+            ))))))
+        )
+        .subcommand(
+            parser_status(parser_ca_dir(
+                SubCommand::with_name("list")
+                    .about("lists the certificates recorded in the ca database"),
+                true,
+            ))
+        )
+        .subcommand(
+            parser_ca_dir(
+                SubCommand::with_name("show")
+                    .about("shows a certificate recorded in the ca database")
+                    .arg(
+                        Arg::with_name("serial")
+                            .required(true)
+                            .index(1)
+                    ),
+                true,
+            )
+        )
+        .subcommand(
+            parser_passphrase(
+                SubCommand::with_name("inspect")
+                    .about("describes a certificate, request, key or crl in PEM or DER format")
+                    .arg(
+                        Arg::with_name("file")
+                            .required(true)
+                            .index(1)
+                    )
+                    .arg(
+                        Arg::with_name("json")
+                            .long("json")
+                            .help("Print a JSON object instead of text")
                     )
             )
         )
+        .subcommand(
+            parser_inform(parser_verify_options(
+                SubCommand::with_name("verify")
+                    .about("verifies that a certificate chains to a trusted ca")
+                    .arg(
+                        Arg::with_name("cert")
+                            .required(true)
+                            .index(1)
+                    )
+                    .arg(
+                        Arg::with_name("ca")
+                            .long("ca")
+                            .value_name("file")
+                            .required(true)
+                            .help("Trusted root certificates in PEM or DER format")
+                    )
+                    .arg(
+                        Arg::with_name("untrusted")
+                            .long("untrusted")
+                            .value_name("file")
+                            .help("Intermediate certificates that may be used to build the path")
+                    )
+            ))
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("exports keys and certificates to other formats")
+                .subcommand(
+                    parser_overwrite(parser_inform(parser_password(parser_passphrase(
+                        SubCommand::with_name("pkcs12")
+                            .about("bundles a private key, its certificate and the ca chain into a PKCS#12 file")
+                            .arg(
+                                Arg::with_name("cert")
+                                    .required(true)
+                                    .index(1)
+                            )
+                            .arg(
+                                Arg::with_name("pkey")
+                                    .required(true)
+                                    .index(2)
+                            )
+                            .arg(
+                                Arg::with_name("output")
+                                    .required(true)
+                                    .index(3)
+                            )
+                            .arg(
+                                Arg::with_name("chain")
+                                    .long("chain")
+                                    .value_name("file")
+                                    .help("CA certificates to include, in PEM format")
+                            )
+                            .arg(
+                                Arg::with_name("name")
+                                    .long("name")
+                                    .value_name("name")
+                                    .help("Friendly name of the key, the certificate common name by default")
+                            )
+                    ))))
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("imports keys and certificates from other formats")
+                .subcommand(
+                    parser_overwrite(parser_outform(parser_cipher(parser_password(parser_passphrase(
+                        SubCommand::with_name("pkcs12")
+                            .about("splits a PKCS#12 file into PEM private key, certificate and ca chain files")
+                            .arg(
+                                Arg::with_name("input")
+                                    .required(true)
+                                    .index(1)
+                            )
+                            .arg(
+                                Arg::with_name("pkey")
+                                    .required(true)
+                                    .index(2)
+                            )
+                            .arg(
+                                Arg::with_name("cert")
+                                    .required(true)
+                                    .index(3)
+                            )
+                            .arg(
+                                Arg::with_name("chain")
+                                    .long("chain")
+                                    .value_name("file")
+                                    .help("Write the CA certificates of the bundle to this file")
+                            )
+                    )))))
+                )
+        )
+        .subcommand(
+            parser_overwrite(parser_inform(parser_outform(parser_ca_dir(parser_pathlen(parser_passphrase(parser_not_after_before(parser_name_constraints(
+                SubCommand::with_name("intermediate")
+                    .about("signs a certificate signing request as an intermediate ca")
+                    .arg(
+                        Arg::with_name("cert")
+                            .required(true)
+                            .index(1)
+                    )
+                    .arg(
+                        Arg::with_name("pkey")
+                            .required(true)
+                            .index(2)
+                    )
+                    .arg(
+                        Arg::with_name("csr")
+                            .required(true)
+                            .index(3)
+                    )
+                    .arg(
+                        Arg::with_name("output")
+                            .required(true)
+                            .index(4)
+                    )
+            )))), false))))
+        )
 
         .subcommand(
-            parser_csr_extensions(
+            parser_overwrite(parser_inform(parser_ca_dir(parser_passphrase(parser_not_after_before(parser_key_spec(parser_csr_extensions(
+                parser_name_builder(
+                    SubCommand::with_name("issue")
+                        .about("generates a private key and a certificate for it signed by the ca in --ca-dir")
+                        .arg(
+                            Arg::with_name("out_dir")
+                                .long("out-dir")
+                                .value_name("dir")
+                                .required(true)
+                                .help("Directory to write privkey.pem, cert.pem, chain.pem and fullchain.pem to")
+                        )
+                )
+            )))), true)))
+        )
+        .subcommand(
+            parser_overwrite(parser_inform(parser_outform(parser_passphrase(parser_csr_extensions(
                 parser_name_builder(
                     SubCommand::with_name("csr")
                         .about("generates a certificate signing request")
@@ -110,60 +557,192 @@ fn main() {
                                 .index(3)
                         )
                 )
-            )
+            )))))
         )
-        .get_matches();
+        .get_matches_safe()
+        .unwrap_or_else(|err| match err.kind {
+            ClapErrorKind::HelpDisplayed | ClapErrorKind::VersionDisplayed => err.exit(),
+            _ => exit_with(CliError::Args(err.into())),
+        });
+
+    if let Err(err) = run(&matches) {
+        exit_with(err);
+    }
+}
 
-    let open_read = OpenOptions::new().read(true).clone();
-    let open_write = OpenOptions::new().write(true).create_new(true).clone();
+fn exit_with(err: CliError) -> ! {
+    eprintln!("{}", err);
+    ::std::process::exit(err.exit_code())
+}
 
+fn run(matches: &ArgMatches) -> Result<(), CliError> {
     if let Some(matches) = matches.subcommand_matches("key") {
         if let Some(matches) = matches.subcommand_matches("gen") {
+            let outform = matches_outform(matches)?;
+
             let file_out = matches.value_of("output").unwrap();
 
-            let pkey = build_privkey().unwrap();
+            let spec = matches_key_spec(matches)?;
+
+            let passphrase = matches_passphrase(matches, true)?;
+            let cipher = matches_cipher(matches)?;
 
-            let mut file = open_write.open(file_out).unwrap();
-            pkey_to_file(&mut file, &pkey).unwrap();
+            let pkey = build_privkey(&spec).step("generating the private key")?;
+
+            let encryption = passphrase.as_ref().map(|passphrase| KeyEncryption { cipher, passphrase });
+
+            let mut file = create_key_output(file_out, matches_overwrite(matches))?;
+            pkey_to_file(&mut file, &pkey, encryption.as_ref(), outform).writing(file_out)?;
+            commit_output(file)?;
         } else if let Some(matches) = matches.subcommand_matches("pub") {
+            let inform = matches_inform(matches)?;
+            let outform = matches_outform(matches)?;
+
             let file_pkey = matches.value_of("pkey").unwrap();
             let file_out = matches.value_of("output").unwrap();
-            let pkey = pkey_from_file(&mut open_read.open(file_pkey).unwrap()).unwrap();
+            let passphrase = matches_passphrase(matches, false)?;
+            let pkey = load_pkey(file_pkey, passphrase.as_deref(), inform)?;
 
 
-            let mut file = open_write.open(file_out).unwrap();
+            let mut file = create_output(file_out, matches_overwrite(matches))?;
 
-            pkey_public_to_file(&mut file, &pkey).unwrap();
+            pkey_public_to_file(&mut file, &pkey, outform).writing(file_out)?;
+            commit_output(file)?;
+        } else {
+            unreachable!("")
+        }
+    } else if let Some(matches) = matches.subcommand_matches("export") {
+        if let Some(matches) = matches.subcommand_matches("pkcs12") {
+            let inform = matches_inform(matches)?;
+
+            let file_cert = matches.value_of("cert").unwrap();
+            let file_pkey = matches.value_of("pkey").unwrap();
+            let file_out = matches.value_of("output").unwrap();
+
+            let cert = cert_from_file(&mut open_input(file_cert)?, inform).reading(file_cert)?;
+            let passphrase = matches_passphrase(matches, false)?;
+            let pkey = load_pkey(file_pkey, passphrase.as_deref(), inform)?;
+
+            let chain = match matches.value_of("chain") {
+                Some(x) => certs_from_file(&mut open_input(x)?, inform).reading(x)?,
+                None => Vec::default(),
+            };
+
+            let name = match matches.value_of("name") {
+                Some(x) => x.to_string(),
+                None => cert.subject_name().entries_by_nid(Nid::COMMONNAME)
+                    .next()
+                    .map(|x| x.data().to_string().unwrap_or_default())
+                    .unwrap_or_default(),
+            };
+
+            let password = matches_password(matches, true)?;
+
+            let pkcs12 = match build_pkcs12(&pkey, &cert, &chain, &name, &password) {
+                Ok(x) => x,
+                Err(LoadError::KeyMismatch) => return Err(CliError::KeyMismatch(file_pkey.to_string(), file_cert.to_string())),
+                Err(err) => return Err(CliError::Step("building the PKCS#12 bundle", err)),
+            };
+
+            let mut file = create_key_output(file_out, matches_overwrite(matches))?;
+
+            pkcs12_to_file(&mut file, &pkcs12).writing(file_out)?;
+            commit_output(file)?;
+        } else {
+            unreachable!("")
+        }
+    } else if let Some(matches) = matches.subcommand_matches("import") {
+        if let Some(matches) = matches.subcommand_matches("pkcs12") {
+            let outform = matches_outform(matches)?;
+
+            let file_in = matches.value_of("input").unwrap();
+            let file_pkey = matches.value_of("pkey").unwrap();
+            let file_cert = matches.value_of("cert").unwrap();
+
+            let password = matches_password(matches, false)?;
+
+            let contents = match pkcs12_from_file(&mut open_input(file_in)?, &password) {
+                Ok(x) => x,
+                Err(LoadError::WrongPassphrase) => return Err(CliError::WrongPassword(file_in.to_string())),
+                Err(err) => return Err(CliError::Read(file_in.to_string(), err)),
+            };
+
+            let (pkey, cert) = match (contents.pkey, contents.cert) {
+                (Some(pkey), Some(cert)) => (pkey, cert),
+                _ => return Err(CliError::Failed(format!("{}: the bundle does not hold both a private key and a certificate", file_in))),
+            };
+
+            let passphrase = matches_passphrase(matches, true)?;
+            let cipher = matches_cipher(matches)?;
+            let encryption = passphrase.as_ref().map(|passphrase| KeyEncryption { cipher, passphrase });
+
+            let overwrite = matches_overwrite(matches);
+
+            let mut file_key = create_key_output(file_pkey, overwrite)?;
+            let mut file_out = create_output(file_cert, overwrite)?;
+            let mut file_chain = match matches.value_of("chain") {
+                Some(x) => Some(create_output(x, overwrite)?),
+                None => None,
+            };
+
+            pkey_to_file(&mut file_key, &pkey, encryption.as_ref(), outform).writing(file_pkey)?;
+            cert_to_file(&mut file_out, &cert, outform).writing(file_cert)?;
+
+            match (&mut file_chain, matches.value_of("chain")) {
+                (Some(file), Some(x)) => certs_to_file(file, &contents.chain, outform).writing(x)?,
+                _ if !contents.chain.is_empty() => {
+                    eprintln!("{}: skipping {} ca certificates, use --chain to keep them", file_in, contents.chain.len());
+                }
+                _ => {}
+            }
+
+            commit_output(file_key)?;
+            commit_output(file_out)?;
+
+            if let Some(file) = file_chain {
+                commit_output(file)?;
+            }
         } else {
             unreachable!("")
         }
     } else if let Some(matches) = matches.subcommand_matches("ca") {
+        let inform = matches_inform(matches)?;
+        let outform = matches_outform(matches)?;
+
         let file_pkey = matches.value_of("pkey").unwrap();
         let file_out = matches.value_of("output").unwrap();
 
-        let pkey = pkey_from_file(&mut open_read.open(file_pkey).unwrap()).unwrap();
+        let name = matches_name_builder(matches)?;
+        let not_a_b = matches_not_after_before(matches)?;
+        let name_constraints = matches_name_constraints(matches)?;
 
-        let name = matches_name_builder(&matches).unwrap();
-        let not_a_b = matches_not_after_before(&matches).unwrap();
+        let passphrase = matches_passphrase(matches, false)?;
+        let pkey = load_ca_pkey(file_pkey, passphrase.as_deref(), inform)?;
 
         let cert = build_ca_cert(
             &pkey,
             &name,
+            name_constraints.as_ref(),
             &not_a_b,
-        ).unwrap();
+        ).step("building the ca certificate")?;
 
-        let mut file = open_write.open(file_out).unwrap();
+        let mut file = create_output(file_out, matches_overwrite(matches))?;
 
-        cert_to_file(&mut file, &cert).unwrap();
+        cert_to_file(&mut file, &cert, outform).writing(file_out)?;
+        commit_output(file)?;
     } else if let Some(matches) = matches.subcommand_matches("csr") {
+        let inform = matches_inform(matches)?;
+        let outform = matches_outform(matches)?;
+
         let file_pkey = matches.value_of("pkey").unwrap();
         let file_out = matches.value_of("output").unwrap();
 
-        let pkey = pkey_from_file(&mut open_read.open(file_pkey).unwrap()).unwrap();
+        let name = matches_name_builder(matches)?;
 
-        let name = matches_name_builder(&matches).unwrap();
+        let exts = matches_csr_extensions(matches)?;
 
-        let exts = matches_csr_extensions(&matches).unwrap();
+        let passphrase = matches_passphrase(matches, false)?;
+        let pkey = load_pkey(file_pkey, passphrase.as_deref(), inform)?;
 
         let csr = build_ca_req(
             &pkey,
@@ -171,45 +750,377 @@ fn main() {
             |cert_builder| {
                 let mut extensions = Stack::<X509Extension>::new()?;
 
-                run_csr_extensions(&exts, &mut extensions, cert_builder)?;
+                run_csr_extensions(&exts, pkey.id(), &mut extensions, cert_builder)?;
 
                 cert_builder.add_extensions(&extensions)?;
 
                 Ok(())
             },
-        ).unwrap();
+        ).step("building the request")?;
 
-        let mut file = open_write.open(file_out).unwrap();
+        let mut file = create_output(file_out, matches_overwrite(matches))?;
 
-        csr_to_file(&mut file, &csr).unwrap();
+        csr_to_file(&mut file, &csr, outform).writing(file_out)?;
+        commit_output(file)?;
     } else if let Some(matches) = matches.subcommand_matches("sign") {
+        let inform = matches_inform(matches)?;
+        let outform = matches_outform(matches)?;
+
+        let file_cert = matches.value_of("cert").unwrap();
+        let file_pkey = matches.value_of("pkey").unwrap();
+        let file_csr = matches.value_of("csr").unwrap();
+        let file_out = matches.value_of("output").unwrap();
+
+        let cert = cert_from_file(&mut open_input(file_cert)?, inform).reading(file_cert)?;
+        let passphrase = matches_passphrase(matches, false)?;
+        let pkey = load_ca_pkey(file_pkey, passphrase.as_deref(), inform)?;
+        let csr = csr_from_file(&mut open_input(file_csr)?, inform).reading(file_csr)?;
+
+        let pubkey = match matches.value_of("pubkey") {
+            Some(x) => Some(pkey_public_from_file(&mut open_input(x)?, inform).reading(x)?),
+            None => None,
+        };
+
+        let subject_key = match &pubkey {
+            Some(pubkey) if matches.is_present("override_pubkey") => SubjectKey::Override(pubkey),
+            Some(pubkey) => SubjectKey::Expect(pubkey),
+            None => SubjectKey::FromRequest,
+        };
+
+        let policy = match matches.value_of("ca_dir") {
+            Some(ca_dir) => Policy::load(ca_dir).ca_dir(Path::new(ca_dir).join("ca.conf"))?,
+            None => Policy::default(),
+        };
+
+        let chain = if ["fullchain", "chain", "bundle"].iter().any(|x| matches.is_present(x)) {
+            match matches.value_of("ca_dir") {
+                Some(ca_dir) => CaDatabase::create(ca_dir).and_then(|x| x.chain(&cert)).ca_dir(ca_dir)?,
+                None => vec![cert.clone()],
+            }
+        } else {
+            Vec::default()
+        };
+
+        let bundle_key = match matches.value_of("bundle_key") {
+            Some(x) => Some((x, load_pkey(x, None, inform)?)),
+            None => None,
+        };
+
+        let not_a_b = issued_validity(matches, &cert, file_cert)?;
+
+        let rcert = signed(file_csr, build_ca_signed_cert(
+            &cert,
+            &pkey,
+            &csr,
+            subject_key,
+            &policy,
+            &not_a_b,
+            |_| { Ok(()) },
+        ))?;
+
+        let bundle = match bundle_key {
+            Some((file_bundle_key, bundle_key)) => {
+                let mut bundle = Vec::<u8>::default();
+
+                match bundle_to_file(&mut bundle, &bundle_key, &rcert, &chain) {
+                    Ok(()) => Some(bundle),
+                    Err(LoadError::KeyMismatch) => return Err(CliError::KeyMismatch(file_bundle_key.to_string(), file_csr.to_string())),
+                    Err(err) => return Err(CliError::Step("building the bundle", err)),
+                }
+            }
+            None => None,
+        };
+
+        let overwrite = matches_overwrite(matches);
+
+        let mut file = create_output(file_out, overwrite)?;
+
+        let mut outputs = ["fullchain", "chain", "bundle"].iter()
+            .filter_map(|x| matches.value_of(x).map(|path| (*x, path)))
+            .map(|(x, path)| match x {
+                "bundle" => Ok((x, path, create_key_output(path, overwrite)?)),
+                _ => Ok((x, path, create_output(path, overwrite)?)),
+            })
+            .collect::<Result<Vec<_>, CliError>>()?;
+
+        cert_to_file(&mut file, &rcert, outform).writing(file_out)?;
+
+        for (output, path, file) in &mut outputs {
+            match (*output, &bundle) {
+                ("fullchain", _) => fullchain_to_file(file, &rcert, &chain),
+                ("chain", _) => certs_to_file(file, &chain, Format::Pem),
+                (_, Some(bundle)) => file.write_all(bundle).map_err(LoadError::from),
+                (_, None) => unreachable!(""),
+            }.writing(path)?;
+        }
+
+        commit_output(file)?;
+
+        for (_, _, file) in outputs {
+            commit_output(file)?;
+        }
+
+        // recorded last, so the index only lists certificates that were delivered
+        if let Some(ca_dir) = matches.value_of("ca_dir") {
+            CaDatabase::create(ca_dir).and_then(|x| x.insert(&rcert)).ca_dir(ca_dir)?;
+        }
+    } else if let Some(matches) = matches.subcommand_matches("issue") {
+        let inform = matches_inform(matches)?;
+
+        let spec = matches_key_spec(matches)?;
+        let name = matches_name_builder(matches)?;
+        let exts = matches_csr_extensions(matches)?;
+
+        let ca_dir = matches.value_of("ca_dir").unwrap();
+        let db = CaDatabase::open(ca_dir).ca_dir(ca_dir)?;
+        let file_out_dir = matches.value_of("out_dir").unwrap();
+        let out_dir = Path::new(file_out_dir);
+
+        let file_cert = db.ca_cert_path().to_string_lossy().to_string();
+        let file_pkey = db.ca_key_path().to_string_lossy().to_string();
+
+        let cert = cert_from_file(&mut open_input(&file_cert)?, inform).reading(&file_cert)?;
+        let passphrase = matches_passphrase(matches, false)?;
+        let pkey = load_ca_pkey(&file_pkey, passphrase.as_deref(), inform)?;
+
+        let chain = db.chain(&cert).ca_dir(ca_dir)?;
+        let policy = Policy::load(db.dir()).ca_dir(db.dir().join("ca.conf"))?;
+
+        let not_a_b = issued_validity(matches, &cert, &file_cert)?;
+
+        let (rkey, rcert) = signed(file_out_dir, build_issued_cert(
+            &cert,
+            &pkey,
+            &spec,
+            &name,
+            &policy,
+            &not_a_b,
+            |req_builder| {
+                let mut extensions = Stack::<X509Extension>::new()?;
+
+                run_csr_extensions(&exts, spec.id(), &mut extensions, req_builder)?;
+
+                req_builder.add_extensions(&extensions)?;
+
+                Ok(())
+            },
+        ))?;
+
+        create_private_dir(out_dir).writing(out_dir)?;
+
+        let (path_key, path_out) = (out_dir.join("privkey.pem"), out_dir.join("cert.pem"));
+        let (path_chain, path_fullchain) = (out_dir.join("chain.pem"), out_dir.join("fullchain.pem"));
+
+        let overwrite = matches_overwrite(matches);
+
+        let mut file_key = create_key_output(&path_key, overwrite)?;
+        let mut file_out = create_output(&path_out, overwrite)?;
+        let mut file_chain = create_output(&path_chain, overwrite)?;
+        let mut file_fullchain = create_output(&path_fullchain, overwrite)?;
+
+        pkey_to_file(&mut file_key, &rkey, None, Format::Pem).writing(&path_key)?;
+        cert_to_file(&mut file_out, &rcert, Format::Pem).writing(&path_out)?;
+        certs_to_file(&mut file_chain, &chain, Format::Pem).writing(&path_chain)?;
+        fullchain_to_file(&mut file_fullchain, &rcert, &chain).writing(&path_fullchain)?;
+
+        for file in [file_key, file_out, file_chain, file_fullchain] {
+            commit_output(file)?;
+        }
+
+        db.insert(&rcert).ca_dir(ca_dir)?;
+    } else if let Some(matches) = matches.subcommand_matches("intermediate") {
+        let inform = matches_inform(matches)?;
+        let outform = matches_outform(matches)?;
+
         let file_cert = matches.value_of("cert").unwrap();
         let file_pkey = matches.value_of("pkey").unwrap();
-        let file_pubkey = matches.value_of("pubkey").unwrap();
         let file_csr = matches.value_of("csr").unwrap();
         let file_out = matches.value_of("output").unwrap();
 
-        let cert = cert_from_file(&mut open_read.open(file_cert).unwrap()).unwrap();
-        let pkey = pkey_from_file(&mut open_read.open(file_pkey).unwrap()).unwrap();
-        let pubkey = pkey_public_from_file(&mut open_read.open(file_pubkey).unwrap()).unwrap();
-        let csr = csr_from_file(&mut open_read.open(file_csr).unwrap()).unwrap();
+        let cert = cert_from_file(&mut open_input(file_cert)?, inform).reading(file_cert)?;
+        let passphrase = matches_passphrase(matches, false)?;
+        let pkey = load_ca_pkey(file_pkey, passphrase.as_deref(), inform)?;
+        let csr = csr_from_file(&mut open_input(file_csr)?, inform).reading(file_csr)?;
+
+        let not_a_b = issued_validity(matches, &cert, file_cert)?;
+        let pathlen = matches_pathlen(matches)?;
+        let name_constraints = matches_name_constraints(matches)?;
 
-        let not_a_b = matches_not_after_before(&matches).unwrap();
+        let policy = match matches.value_of("ca_dir") {
+            Some(ca_dir) => Policy::load(ca_dir).ca_dir(Path::new(ca_dir).join("ca.conf"))?,
+            None => Policy::default(),
+        };
 
-        let rcert = build_ca_signed_cert(
+        let rcert = signed(file_csr, build_intermediate_cert(
             &cert,
             &pkey,
-            &pubkey,
             &csr,
+            pathlen,
+            name_constraints.as_ref(),
+            &policy.urls,
             &not_a_b,
             |_| { Ok(()) },
-        ).unwrap();
+        ))?;
+
+        let mut file = create_output(file_out, matches_overwrite(matches))?;
+
+        cert_to_file(&mut file, &rcert, outform).writing(file_out)?;
+        commit_output(file)?;
+
+        if let Some(ca_dir) = matches.value_of("ca_dir") {
+            CaDatabase::create(ca_dir).and_then(|x| x.insert(&rcert)).ca_dir(ca_dir)?;
+        }
+    } else if let Some(matches) = matches.subcommand_matches("revoke") {
+        let inform = matches_inform(matches)?;
+
+        let target = matches.value_of("target").unwrap();
 
-        let mut file = open_write.open(file_out).unwrap();
+        let ca_dir = matches.value_of("ca_dir").unwrap();
+        let db = CaDatabase::open(ca_dir).ca_dir(ca_dir)?;
+        let reason = matches_revocation_reason(matches)?;
+
+        if Path::new(target).is_file() {
+            let cert = cert_from_file(&mut open_input(target)?, inform).reading(target)?;
+            let serial = cert.serial_number().to_bn().reading(target)?;
+
+            db.revoke(&serial, Some(&cert), reason).ca_dir(ca_dir)?;
+        } else {
+            let serial = parse_serial(target)?;
+
+            db.revoke(&serial, None, reason).ca_dir(ca_dir)?;
+        }
+    } else if let Some(matches) = matches.subcommand_matches("crl") {
+        let inform = matches_inform(matches)?;
+        let outform = matches_outform(matches)?;
+
+        let file_cert = matches.value_of("cert").unwrap();
+        let file_pkey = matches.value_of("pkey").unwrap();
+        let file_out = matches.value_of("output").unwrap();
 
-        cert_to_file(&mut file, &rcert).unwrap();
+        let ca_dir = matches.value_of("ca_dir").unwrap();
+        let db = CaDatabase::open(ca_dir).ca_dir(ca_dir)?;
+        let next_update = matches_crl_options(matches)?;
+
+        let cert = cert_from_file(&mut open_input(file_cert)?, inform).reading(file_cert)?;
+        let passphrase = matches_passphrase(matches, false)?;
+        let pkey = load_ca_pkey(file_pkey, passphrase.as_deref(), inform)?;
+
+        let revoked = db.revoked().ca_dir(ca_dir)?;
+        let crl_number = db.next_crl_number().ca_dir(ca_dir)?;
+
+        let crl = build_crl(
+            &cert,
+            &pkey,
+            &revoked,
+            &crl_number,
+            &next_update,
+        ).step("building the crl")?;
+
+        let mut file = create_output(file_out, matches_overwrite(matches))?;
+
+        crl_to_file(&mut file, &crl, outform).writing(file_out)?;
+        commit_output(file)?;
+    } else if let Some(matches) = matches.subcommand_matches("list") {
+        let ca_dir = matches.value_of("ca_dir").unwrap();
+        let db = CaDatabase::open(ca_dir).ca_dir(ca_dir)?;
+        let status = matches_status(matches)?;
+
+        for record in db.records().ca_dir(ca_dir)? {
+            let current = record.current_status().ca_dir(ca_dir)?;
+
+            if status.is_some_and(|x| x != current) {
+                continue;
+            }
+
+            let sans = match db.cert(&record).ca_dir(ca_dir)? {
+                Some(cert) => san_strings(&cert).join(","),
+                None => String::new(),
+            };
+
+            println!("{}\t{}\t{}\t{}\t{}", record.serial, current.name(), record.not_after, record.subject, sans);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("show") {
+        let ca_dir = matches.value_of("ca_dir").unwrap();
+        let db = CaDatabase::open(ca_dir).ca_dir(ca_dir)?;
+        let serial = parse_serial(matches.value_of("serial").unwrap())?;
+
+        let record = match db.find(&serial).ca_dir(ca_dir)? {
+            Some(record) => record,
+            None => return Err(CliError::Failed(format!("serial {} is not in the ca database", matches.value_of("serial").unwrap()))),
+        };
+
+        println!("Serial:      {}", record.serial);
+        println!("Status:      {}", record.current_status().ca_dir(ca_dir)?.name());
+        println!("Subject:     {}", record.subject);
+
+        if let Some((date, reason)) = &record.revoked {
+            println!("Revoked:     {}", date);
+
+            if let Some(reason) = reason {
+                println!("Reason:      {}", reason.name());
+            }
+        }
+
+        if let Some(cert) = db.cert(&record).ca_dir(ca_dir)? {
+            println!("Not Before:  {}", cert.not_before());
+            println!("Not After:   {}", cert.not_after());
+
+            for san in san_strings(&cert) {
+                println!("SAN:         {}", san);
+            }
+
+            print!("{}", String::from_utf8_lossy(&cert.to_pem().step("encoding the certificate")?));
+        } else if !record.not_after.is_empty() {
+            println!("Not After:   {}", record.not_after);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("inspect") {
+        let file_in = matches.value_of("file").unwrap();
+
+        let mut bytes = Vec::<u8>::default();
+        open_input(file_in)?.read_to_end(&mut bytes).reading(file_in)?;
+
+        let passphrase = matches_passphrase(matches, false)?;
+
+        let object = read_object(&bytes, passphrase.as_deref()).reading(file_in)?;
+        let report = describe(&object).reading(file_in)?;
+
+        if matches.is_present("json") {
+            print!("{}", report.to_json());
+        } else {
+            print!("{}", report.to_text());
+        }
+    } else if let Some(matches) = matches.subcommand_matches("verify") {
+        let inform = matches_inform(matches)?;
+
+        let file_cert = matches.value_of("cert").unwrap();
+        let file_ca = matches.value_of("ca").unwrap();
+
+        let cert = cert_from_file(&mut open_input(file_cert)?, inform).reading(file_cert)?;
+        let trusted = certs_from_file(&mut open_input(file_ca)?, inform).reading(file_ca)?;
+        let untrusted = match matches.value_of("untrusted") {
+            Some(x) => certs_from_file(&mut open_input(x)?, inform).reading(x)?,
+            None => Vec::default(),
+        };
+
+        let options = matches_verify_options(matches)?;
+        let verification = verify_chain(&cert, &trusted, &untrusted, &options).step("verifying the certificate")?;
+
+        for (depth, x) in verification.path.iter().enumerate() {
+            println!("{:<11}{}", if depth == 0 { "Path:" } else { "" }, name_to_string(x.subject_name()));
+        }
+
+        let not_after = asn1_to_rfc3339(cert.not_after()).reading(file_cert)?;
+        let days_left = Asn1Time::days_from_now(0).and_then(|x| x.diff(cert.not_after())).reading(file_cert)?.days;
+        println!("{:<11}{} ({} days left)", "Expires:", not_after, days_left);
+
+        if !verification.is_valid() {
+            return Err(CliError::Verify(file_cert.to_string(), verification.errors));
+        }
+
+        println!("OK");
     } else {
-        eprintln!("invalid command");
-        ::std::process::exit(-1);
+        return Err(CliError::Failed("invalid command".to_string()));
     }
-}
\ No newline at end of file
+
+    Ok(())
+}
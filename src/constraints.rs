@@ -0,0 +1,445 @@
+//! NameConstraints of CA certificates, see RFC 5280 section 4.2.1.10
+
+use openssl::asn1::{Asn1Object, Asn1OctetString};
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::stack::StackRef;
+use openssl::x509::{GeneralName, X509Extension, X509NameRef};
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::der::{read_tlv, tlv, TAG_SEQUENCE, TAG_URI};
+use crate::{general_name_upn, name_to_string};
+
+const TAG_PERMITTED: u8 = 0xa0;
+const TAG_EXCLUDED: u8 = 0xa1;
+// GeneralName choices, implicitly tagged
+const TAG_EMAIL: u8 = 0x81;
+const TAG_DNS: u8 = 0x82;
+const TAG_IP: u8 = 0x87;
+const TAG_DIRECTORY_NAME: u8 = 0xa4;
+
+/// Name of a GeneralName choice by its tag, for those without a variant of their own
+fn general_name_type(tag: u8) -> &'static str {
+    match tag {
+        0xa0 => "otherName",
+        0xa3 => "x400Address",
+        TAG_DIRECTORY_NAME => "dirName",
+        0xa5 => "ediPartyName",
+        0x88 => "registeredID",
+        _ => "unknown GeneralName",
+    }
+}
+
+/// A subtree of names, the base of a GeneralSubtree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Subtree {
+    /// A domain and its subdomains, or only its subdomains when it starts with a dot
+    Dns(String),
+    /// A mailbox, every mailbox at a host, or at the subdomains of a domain when it starts with a dot
+    Email(String),
+    /// URIs with the given host, or with a subdomain of it when it starts with a dot
+    Uri(String),
+    /// Addresses of a network, given by its address and prefix length
+    Ip(IpAddr, u8),
+    /// A name type that is not checked when signing, by its GeneralName tag and encoded value
+    Other(u8, Vec<u8>),
+}
+
+/// Address with all but the first `prefix` bits cleared
+fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(x) => IpAddr::from((u32::from(x) & u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)).to_be_bytes()),
+        IpAddr::V6(x) => IpAddr::from((u128::from(x) & u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)).to_be_bytes()),
+    }
+}
+
+/// Whether `addr` is in the network `base`/`prefix`
+pub(crate) fn in_network(addr: IpAddr, base: IpAddr, prefix: u8) -> bool {
+    base.is_ipv4() == addr.is_ipv4() && mask(addr, prefix) == base
+}
+
+fn prefix_mask(addr: IpAddr, prefix: u8) -> Vec<u8> {
+    let len = if addr.is_ipv4() { 4 } else { 16 };
+
+    (0..len).map(|i| {
+        let bits = (prefix as usize).saturating_sub(i * 8).min(8);
+        (0xff00u16 >> bits) as u8
+    }).collect()
+}
+
+/// Whether `name` falls under the domain `base`, its subdomains only when `base` starts with a dot
+fn in_subtree(name: &str, base: &str, exact_without_dot: bool) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let base = base.trim_end_matches('.').to_ascii_lowercase();
+
+    if base.is_empty() {
+        true
+    } else if base.starts_with('.') {
+        name.ends_with(&base)
+    } else {
+        name == base || (!exact_without_dot && name.ends_with(&format!(".{}", base)))
+    }
+}
+
+/// Host part of a URI, without user information or port
+pub(crate) fn uri_host(uri: &str) -> Option<&str> {
+    let rest = &uri[uri.find("://")? + 3..];
+    let authority = &rest[..rest.find(['/', '?', '#']).unwrap_or(rest.len())];
+    let host = authority.rsplit('@').next()?;
+
+    let host = match host.strip_prefix('[') {
+        Some(x) => &x[..x.find(']')?],
+        None => host.split(':').next()?,
+    };
+
+    if host.is_empty() { None } else { Some(host) }
+}
+
+impl Subtree {
+    /// Parse a subtree given as `DNS:example.com`, `email:example.com`, `URI:.example.com` or
+    /// `IP:10.0.0.0/8`
+    pub fn parse(x: &str) -> Result<Subtree, String> {
+        let (kind, value) = match x.find(':') {
+            Some(i) => (&x[..i], &x[i + 1..]),
+            None => return Err(format!("{} is not of the form DNS:x, IP:x, email:x or URI:x", x)),
+        };
+
+        if value.is_empty() {
+            return Err(format!("{} has no value", x));
+        }
+
+        let subtree = match kind {
+            // a wildcard covers the same names as the subdomains of its domain
+            "DNS" => Subtree::Dns(value.strip_prefix('*').unwrap_or(value).to_string()),
+            "email" => Subtree::Email(value.to_string()),
+            "URI" => Subtree::Uri(value.to_string()),
+            "IP" => {
+                let (addr, prefix) = match value.find('/') {
+                    Some(i) => (&value[..i], Some(&value[i + 1..])),
+                    None => (value, None),
+                };
+
+                let addr = addr.parse::<IpAddr>().map_err(|_| format!("{} is not an IP address", addr))?;
+                let max = if addr.is_ipv4() { 32 } else { 128 };
+
+                let prefix = match prefix {
+                    Some(x) => x.parse::<u8>().ok().filter(|x| *x <= max).ok_or_else(|| format!("{} is not a prefix length", x))?,
+                    None => max,
+                };
+
+                Subtree::Ip(mask(addr, prefix), prefix)
+            }
+            _ => return Err(format!("{} is not one of DNS, IP, email or URI", kind)),
+        };
+
+        Ok(subtree)
+    }
+
+    fn to_der(&self) -> Vec<u8> {
+        let base = match self {
+            Subtree::Dns(x) => tlv(TAG_DNS, x.as_bytes()),
+            Subtree::Email(x) => tlv(TAG_EMAIL, x.as_bytes()),
+            Subtree::Uri(x) => tlv(TAG_URI, x.as_bytes()),
+            Subtree::Ip(addr, prefix) => {
+                let mut value = match addr {
+                    IpAddr::V4(x) => x.octets().to_vec(),
+                    IpAddr::V6(x) => x.octets().to_vec(),
+                };
+                value.extend(prefix_mask(*addr, *prefix));
+                tlv(TAG_IP, &value)
+            }
+            Subtree::Other(tag, value) => tlv(*tag, value),
+        };
+
+        tlv(TAG_SEQUENCE, &base)
+    }
+
+    fn from_der(tag: u8, value: &[u8]) -> Option<Subtree> {
+        let text = || String::from_utf8(value.to_vec()).ok();
+
+        let subtree = match tag {
+            TAG_DNS => Subtree::Dns(text()?),
+            TAG_EMAIL => Subtree::Email(text()?),
+            TAG_URI => Subtree::Uri(text()?),
+            TAG_IP => {
+                let (addr, mask) = match value.len() {
+                    8 => (IpAddr::from(<[u8; 4]>::try_from(&value[..4]).ok()?), &value[4..]),
+                    32 => (IpAddr::from(<[u8; 16]>::try_from(&value[..16]).ok()?), &value[16..]),
+                    _ => return None,
+                };
+
+                Subtree::Ip(addr, mask.iter().map(|x| x.count_ones() as u8).sum())
+            }
+            _ => Subtree::Other(tag, value.to_vec()),
+        };
+
+        Some(subtree)
+    }
+
+    fn matches(&self, name: &Name) -> bool {
+        match (self, name) {
+            (Subtree::Dns(base), Name::Dns(x)) => in_subtree(x, base, false),
+            (Subtree::Email(base), Name::Email(x)) if base.contains('@') => base.eq_ignore_ascii_case(x),
+            (Subtree::Email(base), Name::Email(x)) => in_subtree(x.rsplit('@').next().unwrap_or_default(), base, true),
+            (Subtree::Uri(base), Name::Uri(x)) => uri_host(x).is_some_and(|host| in_subtree(host, base, true)),
+            (Subtree::Ip(base, prefix), Name::Ip(x)) => in_network(*x, *base, *prefix),
+            _ => false,
+        }
+    }
+
+    /// Whether `self` and `other` constrain the same type of name
+    fn same_type(&self, other: &Subtree) -> bool {
+        match (self, other) {
+            (Subtree::Other(x, _), Subtree::Other(y, _)) => x == y,
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+
+    /// Whether every name under `self` is also under `other`
+    fn within(&self, other: &Subtree) -> bool {
+        // the domain a subtree of subdomains only starts below
+        let subdomains = |x: &str| x.strip_prefix('.').map(str::to_string);
+
+        match (self, other) {
+            (Subtree::Dns(x), Subtree::Dns(base)) => match subdomains(x) {
+                Some(x) => in_subtree(&x, base.trim_start_matches('.'), false),
+                None => in_subtree(x, base, false),
+            },
+            (Subtree::Email(x), Subtree::Email(_)) if x.contains('@') => other.matches(&Name::Email(x)),
+            (Subtree::Email(_), Subtree::Email(base)) if base.contains('@') => false,
+            (Subtree::Email(x), Subtree::Email(base)) | (Subtree::Uri(x), Subtree::Uri(base)) => match (subdomains(x), subdomains(base)) {
+                (Some(x), Some(base)) => in_subtree(&x, &base, false),
+                (Some(_), None) => base.is_empty(),
+                (None, _) => in_subtree(x, base, true),
+            },
+            (Subtree::Ip(x, prefix), Subtree::Ip(base, base_prefix)) => {
+                x.is_ipv4() == base.is_ipv4() && prefix >= base_prefix && mask(*x, *base_prefix) == *base
+            }
+            _ => self == other,
+        }
+    }
+
+    fn covers(&self, name: &Name) -> bool {
+        matches!(
+            (self, name),
+            (Subtree::Dns(_), Name::Dns(_)) | (Subtree::Email(_), Name::Email(_)) |
+            (Subtree::Uri(_), Name::Uri(_)) | (Subtree::Ip(..), Name::Ip(_))
+        )
+    }
+}
+
+impl fmt::Display for Subtree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Subtree::Dns(x) => write!(f, "DNS:{}", x),
+            Subtree::Email(x) => write!(f, "email:{}", x),
+            Subtree::Uri(x) => write!(f, "URI:{}", x),
+            Subtree::Ip(addr, prefix) => write!(f, "IP:{}/{}", addr, prefix),
+            Subtree::Other(tag, _) => write!(f, "{}", general_name_type(*tag)),
+        }
+    }
+}
+
+/// A name of a certificate checked against the constraints
+enum Name<'a> {
+    Dns(&'a str),
+    Email(&'a str),
+    Uri(&'a str),
+    Ip(IpAddr),
+    /// A subjectAltName of a type the constraints are not evaluated for, rendered for messages
+    Unsupported(String),
+}
+
+impl fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Name::Dns(x) => write!(f, "DNS:{}", x),
+            Name::Email(x) => write!(f, "email:{}", x),
+            Name::Uri(x) => write!(f, "URI:{}", x),
+            Name::Ip(x) => write!(f, "IP:{}", x),
+            Name::Unsupported(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+/// Whether a common name is meant as a host name, which it is taken for without DNS names in
+/// the subjectAltName
+pub(crate) fn is_host_name(x: &str) -> bool {
+    x.contains('.') && x.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '*')
+}
+
+/// Subtrees a CA may issue certificates for
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NameConstraints {
+    /// Names of a type must fall under one of these, if any of that type are given
+    pub permitted: Vec<Subtree>,
+    /// Names must not fall under any of these
+    pub excluded: Vec<Subtree>,
+}
+
+impl NameConstraints {
+    pub fn is_empty(&self) -> bool {
+        self.permitted.is_empty() && self.excluded.is_empty()
+    }
+
+    /// Encode the constraints as a critical nameConstraints extension
+    pub fn to_extension(&self) -> Result<X509Extension, ErrorStack> {
+        let subtrees = |tag: u8, subtrees: &[Subtree]| match subtrees {
+            [] => Vec::default(),
+            _ => tlv(tag, &subtrees.iter().flat_map(|x| x.to_der()).collect::<Vec<_>>()),
+        };
+
+        let mut content = subtrees(TAG_PERMITTED, &self.permitted);
+        content.extend(subtrees(TAG_EXCLUDED, &self.excluded));
+
+        let oid = Asn1Object::from_str("2.5.29.30")?;
+        let value = Asn1OctetString::new_from_bytes(&tlv(TAG_SEQUENCE, &content))?;
+
+        X509Extension::new_from_der(&oid, true, &value)
+    }
+
+    /// Decode the value of a nameConstraints extension
+    pub fn from_der(der: &[u8]) -> Option<NameConstraints> {
+        let (tag, mut body, rest) = read_tlv(der)?;
+
+        if tag != TAG_SEQUENCE || !rest.is_empty() {
+            return None;
+        }
+
+        let mut constraints = NameConstraints::default();
+
+        while !body.is_empty() {
+            let (tag, mut subtrees, rest) = read_tlv(body)?;
+            body = rest;
+
+            let target = match tag {
+                TAG_PERMITTED => &mut constraints.permitted,
+                TAG_EXCLUDED => &mut constraints.excluded,
+                _ => return None,
+            };
+
+            while !subtrees.is_empty() {
+                let (tag, subtree, rest) = read_tlv(subtrees)?;
+                subtrees = rest;
+
+                if tag != TAG_SEQUENCE {
+                    return None;
+                }
+
+                // minimum and maximum are not used in this profile
+                let (tag, value, _) = read_tlv(subtree)?;
+                target.push(Subtree::from_der(tag, value)?);
+            }
+        }
+
+        Some(constraints)
+    }
+
+    /// Check the names a certificate would carry, returning a message for each one outside the
+    /// constraints.
+    ///
+    /// The emailAddress attributes of the subject count as email addresses, and its common names
+    /// as DNS names when `sans` holds none and they look like host names.
+    pub fn check(&self, subject: &X509NameRef, sans: Option<&StackRef<GeneralName>>) -> Vec<String> {
+        let mut names = Vec::<Name>::default();
+
+        for name in sans.into_iter().flatten() {
+            if let Some(x) = name.dnsname() {
+                names.push(Name::Dns(x));
+            } else if let Some(x) = name.email() {
+                names.push(Name::Email(x));
+            } else if let Some(x) = name.uri() {
+                names.push(Name::Uri(x));
+            } else if let Some(x) = name.ipaddress() {
+                match x.len() {
+                    4 => names.push(Name::Ip(IpAddr::from(<[u8; 4]>::try_from(x).unwrap()))),
+                    16 => names.push(Name::Ip(IpAddr::from(<[u8; 16]>::try_from(x).unwrap()))),
+                    _ => names.push(Name::Unsupported(format!("IP:{:02X?}", x))),
+                }
+            } else if let Some(x) = name.directory_name() {
+                names.push(Name::Unsupported(format!("DirName:{}", name_to_string(x))));
+            } else if let Some(x) = general_name_upn(name) {
+                names.push(Name::Unsupported(format!("UPN:{}", x)));
+            } else {
+                names.push(Name::Unsupported("otherName".to_string()));
+            }
+        }
+
+        let has_dns = names.iter().any(|x| matches!(x, Name::Dns(_)));
+        let subject_names = subject.entries()
+            .filter_map(|entry| Some((entry.object().nid(), std::str::from_utf8(entry.data().as_slice()).ok()?)))
+            .filter_map(|(nid, value)| match nid {
+                Nid::PKCS9_EMAILADDRESS => Some(Name::Email(value)),
+                Nid::COMMONNAME if !has_dns && is_host_name(value) => Some(Name::Dns(value)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        names.extend(subject_names);
+
+        let mut violations = Vec::<String>::default();
+
+        // names are refused rather than passed when the constraints cannot be evaluated for them
+        let subtrees = self.permitted.iter().chain(&self.excluded);
+
+        if let Some(subtree) = subtrees.clone().find(|x| matches!(x, Subtree::Other(TAG_DIRECTORY_NAME, _))) {
+            if subject.entries().next().is_some() {
+                violations.push(format!("the subject cannot be checked against the {} name constraint", subtree));
+            }
+        }
+
+        for name in &names {
+            if let Name::Unsupported(_) = name {
+                if subtrees.clone().next().is_some() {
+                    violations.push(format!("{} cannot be checked against the name constraints", name));
+                }
+            } else if let Some(subtree) = self.excluded.iter().find(|x| x.matches(name)) {
+                violations.push(format!("{} is excluded by the name constraint {}", name, subtree));
+            } else if self.permitted.iter().any(|x| x.covers(name)) && !self.permitted.iter().any(|x| x.matches(name)) {
+                violations.push(format!("{} is outside the permitted name constraints", name));
+            }
+        }
+
+        violations
+    }
+
+    /// Add the subtrees of `issuer` that still apply to a subordinate CA with these constraints:
+    /// permitted subtrees of the types these do not permit and every excluded subtree
+    pub fn inherit(mut self, issuer: &NameConstraints) -> NameConstraints {
+        let permitted = issuer.permitted.iter()
+            .filter(|x| !self.permitted.iter().any(|y| y.same_type(x)))
+            .cloned()
+            .collect::<Vec<_>>();
+        let excluded = issuer.excluded.iter()
+            .filter(|x| !self.excluded.contains(x))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        self.permitted.extend(permitted);
+        self.excluded.extend(excluded);
+        self
+    }
+
+    /// Check the permitted subtrees of a subordinate CA, returning a message for each one that
+    /// reaches outside these constraints.
+    ///
+    /// Verifiers would reject the names the subordinate CA issues there anyway, but refusing it
+    /// keeps it from claiming more than it may use.
+    pub fn check_subordinate(&self, child: &NameConstraints) -> Vec<String> {
+        let mut violations = Vec::<String>::default();
+
+        for subtree in &child.permitted {
+            let same_type = self.permitted.iter().filter(|x| x.same_type(subtree)).collect::<Vec<_>>();
+
+            if let Some(base) = self.excluded.iter().find(|x| subtree.within(x)) {
+                violations.push(format!("name constraint {} is excluded by the name constraint {}", subtree, base));
+            } else if !same_type.is_empty() && !same_type.iter().any(|x| subtree.within(x)) {
+                violations.push(format!("name constraint {} is outside the permitted name constraints", subtree));
+            }
+        }
+
+        violations
+    }
+}
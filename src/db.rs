@@ -0,0 +1,461 @@
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, BigNumRef};
+use openssl::x509::{X509, X509Ref};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{LoadError, RevocationReason, RevokedCert, name_to_string};
+use crate::time::asn1_to_string;
+use crate::output::create_private_dir;
+
+/// Status column of the CA index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Valid,
+    Revoked,
+    Expired,
+}
+
+impl Status {
+    pub fn name(self) -> &'static str {
+        match self {
+            Status::Valid => "valid",
+            Status::Revoked => "revoked",
+            Status::Expired => "expired",
+        }
+    }
+
+    fn flag(self) -> &'static str {
+        match self {
+            Status::Valid => "V",
+            Status::Revoked => "R",
+            Status::Expired => "E",
+        }
+    }
+
+    fn from_flag(x: &str) -> Option<Self> {
+        match x {
+            "V" => Some(Status::Valid),
+            "R" => Some(Status::Revoked),
+            "E" => Some(Status::Expired),
+            _ => None,
+        }
+    }
+}
+
+/// A line of the CA index.
+///
+/// The layout follows OpenSSL's `index.txt`: status, expiry, revocation date and reason,
+/// serial number in hex, file name and subject, separated by tabs. Tabs, line breaks and
+/// backslashes in the subject are escaped as `\t`, `\n`, `\r` and `\\`.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub status: Status,
+    pub not_after: String,
+    pub revoked: Option<(String, Option<RevocationReason>)>,
+    pub serial: String,
+    pub file: String,
+    pub subject: String,
+}
+
+impl Record {
+    fn parse(line: &str) -> Result<Self, LoadError> {
+        let invalid = || LoadError::Database(format!("malformed index line: {}", line));
+
+        let fields = line.split('\t').collect::<Vec<_>>();
+
+        if fields.len() != 6 {
+            return Err(invalid());
+        }
+
+        let status = Status::from_flag(fields[0]).ok_or_else(invalid)?;
+
+        let revoked = if fields[2].is_empty() {
+            None
+        } else {
+            let mut parts = fields[2].splitn(2, ',');
+            let date = parts.next().unwrap_or_default().to_string();
+            let reason = match parts.next() {
+                Some(x) => Some(RevocationReason::from_name(x).ok_or_else(invalid)?),
+                None => None,
+            };
+
+            Some((date, reason))
+        };
+
+        Ok(Record {
+            status,
+            not_after: fields[1].to_string(),
+            revoked,
+            serial: fields[3].to_string(),
+            file: fields[4].to_string(),
+            subject: unescape_field(fields[5]),
+        })
+    }
+
+    fn to_line(&self) -> String {
+        let revoked = match &self.revoked {
+            Some((date, Some(reason))) => format!("{},{}", date, reason.name()),
+            Some((date, None)) => date.clone(),
+            None => String::new(),
+        };
+
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            self.status.flag(), self.not_after, revoked, self.serial, self.file, escape_field(&self.subject),
+        )
+    }
+
+    pub fn serial_number(&self) -> Result<BigNum, LoadError> {
+        Ok(BigNum::from_hex_str(&self.serial)?)
+    }
+
+    /// Status taking expiry into account: valid certificates past their notAfter are expired
+    pub fn current_status(&self) -> Result<Status, LoadError> {
+        if self.status != Status::Valid || self.not_after.is_empty() {
+            return Ok(self.status);
+        }
+
+        let not_after = Asn1Time::from_str(&self.not_after)?;
+
+        if not_after < Asn1Time::days_from_now(0)? {
+            Ok(Status::Expired)
+        } else {
+            Ok(Status::Valid)
+        }
+    }
+}
+
+fn escape_field(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+
+    for x in value.chars() {
+        match x {
+            '\\' => res.push_str("\\\\"),
+            '\t' => res.push_str("\\t"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            _ => res.push(x),
+        }
+    }
+
+    res
+}
+
+/// Reverse `escape_field`; other backslashes are kept, as written by older versions
+fn unescape_field(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+
+    while let Some(x) = chars.next() {
+        let unescaped = match (x, chars.peek()) {
+            ('\\', Some('\\')) => '\\',
+            ('\\', Some('t')) => '\t',
+            ('\\', Some('n')) => '\n',
+            ('\\', Some('r')) => '\r',
+            _ => {
+                res.push(x);
+                continue;
+            }
+        };
+
+        chars.next();
+        res.push(unescaped);
+    }
+
+    res
+}
+
+/// Exclusive lock on the state directory, held while the index or CRL number is updated
+/// and released when dropped
+struct DatabaseLock {
+    _file: File,
+}
+
+impl DatabaseLock {
+    fn acquire(path: &Path) -> Result<Self, LoadError> {
+        let file = OpenOptions::new().write(true).create(true).truncate(false).open(path)?;
+
+        // blocks until a concurrent sign, revoke or crl is done
+        #[cfg(unix)]
+        unsafe {
+            use std::os::unix::io::AsRawFd;
+
+            if libc::flock(file.as_raw_fd(), libc::LOCK_EX) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+
+        Ok(DatabaseLock { _file: file })
+    }
+}
+
+/// Format a serial number as even-length upper-case hex, as OpenSSL does
+pub fn serial_to_hex(serial: &BigNumRef) -> Result<String, LoadError> {
+    let hex = serial.to_hex_str()?.to_string();
+
+    Ok(if hex.len() % 2 == 1 { format!("0{}", hex) } else { hex })
+}
+
+/// State directory of a CA.
+///
+/// `index.txt` lists every issued or revoked certificate, `certs/<serial>.pem` keeps a copy of
+/// each issued certificate and `crlnumber` holds the number of the next CRL. An optional
+/// `chain.pem` holds the CA certificate followed by its issuers, and `ca.crt` and `ca.key` may
+/// hold the CA certificate and private key for `simpleca issue`. Updates take an exclusive lock
+/// on `.lock`.
+pub struct CaDatabase {
+    dir: PathBuf,
+}
+
+impl CaDatabase {
+    /// Open an existing state directory
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, LoadError> {
+        let dir = dir.as_ref();
+
+        match fs::metadata(dir) {
+            Ok(metadata) if metadata.is_dir() => Ok(CaDatabase { dir: dir.to_path_buf() }),
+            Ok(_) => Err(LoadError::Database("not a directory".to_string())),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Err(LoadError::Database("no such directory".to_string())),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Open the state directory, creating it if it does not exist yet
+    pub fn create<P: AsRef<Path>>(dir: P) -> Result<Self, LoadError> {
+        create_private_dir(dir.as_ref())?;
+
+        Self::open(dir)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.dir.join("index.txt")
+    }
+
+    fn crl_number_path(&self) -> PathBuf {
+        self.dir.join("crlnumber")
+    }
+
+    fn certs_dir(&self) -> PathBuf {
+        self.dir.join("certs")
+    }
+
+    pub fn ca_cert_path(&self) -> PathBuf {
+        self.dir.join("ca.crt")
+    }
+
+    pub fn ca_key_path(&self) -> PathBuf {
+        self.dir.join("ca.key")
+    }
+
+    fn chain_path(&self) -> PathBuf {
+        self.dir.join("chain.pem")
+    }
+
+    fn lock(&self) -> Result<DatabaseLock, LoadError> {
+        DatabaseLock::acquire(&self.dir.join(".lock"))
+    }
+
+    fn read(&self, path: &Path) -> Result<Option<String>, LoadError> {
+        match OpenOptions::new().read(true).open(path) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)?;
+                Ok(Some(contents))
+            }
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Replace `path` by writing a sibling file first and renaming it over
+    fn replace(&self, path: &Path, contents: &str) -> Result<(), LoadError> {
+        let tmp = path.with_extension("new");
+
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+
+    pub fn records(&self) -> Result<Vec<Record>, LoadError> {
+        let contents = self.read(&self.index_path())?.unwrap_or_default();
+
+        contents.lines()
+            .filter(|line| !line.is_empty())
+            .map(Record::parse)
+            .collect()
+    }
+
+    fn write_records(&self, records: &[Record]) -> Result<(), LoadError> {
+        let mut contents = String::new();
+
+        for record in records {
+            contents.push_str(&record.to_line());
+            contents.push('\n');
+        }
+
+        self.replace(&self.index_path(), &contents)
+    }
+
+    /// Record a newly issued certificate and keep a copy of it under `certs/`
+    pub fn insert(&self, cert: &X509Ref) -> Result<Record, LoadError> {
+        let serial = serial_to_hex(cert.serial_number().to_bn()?.as_ref())?;
+
+        let _lock = self.lock()?;
+        let mut records = self.records()?;
+
+        if self.find_in(&records, &serial).is_some() {
+            return Err(LoadError::Database(format!("serial {} is already in the index", serial)));
+        }
+
+        fs::create_dir_all(self.certs_dir())?;
+
+        let file = format!("certs/{}.pem", serial);
+        let mut out = OpenOptions::new().write(true).create_new(true).open(self.dir.join(&file))?;
+        out.write_all(cert.to_pem()?.as_ref())?;
+
+        let record = Record {
+            status: Status::Valid,
+            not_after: asn1_to_string(cert.not_after())?,
+            revoked: None,
+            serial,
+            file,
+            subject: name_to_string(cert.subject_name()),
+        };
+
+        records.push(record.clone());
+        self.write_records(&records)?;
+
+        Ok(record)
+    }
+
+    fn find_in<'a>(&self, records: &'a [Record], serial: &str) -> Option<&'a Record> {
+        records.iter().find(|x| x.serial.eq_ignore_ascii_case(serial))
+    }
+
+    /// Look up a record by its serial number
+    pub fn find(&self, serial: &BigNumRef) -> Result<Option<Record>, LoadError> {
+        let serial = serial_to_hex(serial)?;
+
+        Ok(self.find_in(&self.records()?, &serial).cloned())
+    }
+
+    /// The stored copy of an issued certificate, if the index has one for it
+    pub fn cert(&self, record: &Record) -> Result<Option<X509>, LoadError> {
+        if record.file == "unknown" {
+            return Ok(None);
+        }
+
+        match self.read(&self.dir.join(&record.file))? {
+            Some(pem) => Ok(Some(X509::from_pem(pem.as_bytes())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Certificates to serve after a leaf issued by `ca_cert`: `chain.pem` when the directory has
+    /// one, `ca_cert` alone otherwise
+    pub fn chain(&self, ca_cert: &X509Ref) -> Result<Vec<X509>, LoadError> {
+        let chain = match self.read(&self.chain_path())? {
+            Some(pem) => X509::stack_from_pem(pem.as_bytes())?,
+            None => return Ok(vec![ca_cert.to_owned()]),
+        };
+
+        match chain.first() {
+            Some(first) if first.to_der()? == ca_cert.to_der()? => Ok(chain),
+            _ => Err(LoadError::Config("chain.pem does not start with the ca certificate".to_string())),
+        }
+    }
+
+    /// Mark a certificate as revoked as of now.
+    ///
+    /// Serials that are not in the index yet are added, using `cert` for the expiry
+    /// and subject columns when it is available.
+    pub fn revoke(
+        &self,
+        serial: &BigNumRef,
+        cert: Option<&X509Ref>,
+        reason: Option<RevocationReason>,
+    ) -> Result<(), LoadError> {
+        let serial = serial_to_hex(serial)?;
+        let now = Asn1Time::days_from_now(0)?;
+        let now = asn1_to_string(&now)?;
+
+        let _lock = self.lock()?;
+        let mut records = self.records()?;
+
+        let record = match records.iter().position(|x| x.serial.eq_ignore_ascii_case(&serial)) {
+            Some(idx) => &mut records[idx],
+            None => {
+                let (not_after, subject) = match cert {
+                    Some(cert) => (asn1_to_string(cert.not_after())?, name_to_string(cert.subject_name())),
+                    None => (String::new(), String::new()),
+                };
+
+                records.push(Record {
+                    status: Status::Valid,
+                    not_after,
+                    revoked: None,
+                    serial: serial.clone(),
+                    file: "unknown".to_string(),
+                    subject,
+                });
+
+                records.last_mut().unwrap()
+            }
+        };
+
+        if record.status == Status::Revoked {
+            return Err(LoadError::Database(format!("certificate {} is already revoked", serial)));
+        }
+
+        record.status = Status::Revoked;
+        record.revoked = Some((now, reason));
+
+        self.write_records(&records)
+    }
+
+    /// All revoked certificates, as entries for `build_crl`
+    pub fn revoked(&self) -> Result<Vec<RevokedCert>, LoadError> {
+        let mut res = Vec::<RevokedCert>::default();
+
+        for record in self.records()? {
+            if let (Status::Revoked, Some((date, reason))) = (record.status, &record.revoked) {
+                res.push(RevokedCert {
+                    serial: record.serial_number()?,
+                    date: Asn1Time::from_str(date)?,
+                    reason: *reason,
+                });
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Take the next CRL number, starting at 1, and advance the counter
+    pub fn next_crl_number(&self) -> Result<BigNum, LoadError> {
+        let path = self.crl_number_path();
+
+        let _lock = self.lock()?;
+        let current = match self.read(&path)? {
+            Some(x) => BigNum::from_hex_str(x.trim())
+                .map_err(|_| LoadError::Database(format!("malformed crlnumber: {}", x.trim())))?,
+            None => BigNum::from_u32(1)?,
+        };
+
+        let mut next = BigNum::new()?;
+        next.checked_add(&current, BigNum::from_u32(1)?.as_ref())?;
+
+        self.replace(&path, &format!("{}\n", serial_to_hex(&next)?))?;
+
+        Ok(current)
+    }
+}
@@ -0,0 +1,53 @@
+//! Minimal DER encoding and decoding for extension values the openssl crate has no builder for
+
+pub const TAG_OID: u8 = 0x06;
+pub const TAG_UTF8_STRING: u8 = 0x0c;
+pub const TAG_SEQUENCE: u8 = 0x30;
+/// otherName choice of a GeneralName, also the explicit tag of its value
+pub const TAG_OTHER_NAME: u8 = 0xa0;
+/// uniformResourceIdentifier choice of a GeneralName, implicitly tagged
+pub const TAG_URI: u8 = 0x86;
+
+/// Encode a tag-length-value triple with a definite length
+pub fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut res = vec![tag];
+    let len = content.len();
+
+    if len < 0x80 {
+        res.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|x| **x == 0).count();
+
+        res.push(0x80 | (bytes.len() - skip) as u8);
+        res.extend_from_slice(&bytes[skip..]);
+    }
+
+    res.extend_from_slice(content);
+    res
+}
+
+/// Split the first tag-length-value triple off `input`, as (tag, content, rest)
+pub fn read_tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+
+    let (len, rest) = if len < 0x80 {
+        (len as usize, rest)
+    } else {
+        let count = (len & 0x7f) as usize;
+
+        if count == 0 || count > std::mem::size_of::<usize>() || rest.len() < count {
+            return None;
+        }
+
+        let len = rest[..count].iter().fold(0usize, |acc, x| acc << 8 | *x as usize);
+        (len, &rest[count..])
+    };
+
+    if rest.len() < len {
+        return None;
+    }
+
+    Some((tag, &rest[..len], &rest[len..]))
+}
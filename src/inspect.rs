@@ -0,0 +1,374 @@
+//! Descriptions of certificates, requests, keys and CRLs for `simpleca inspect`
+
+use openssl::asn1::{Asn1BitString, Asn1Object};
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{HasPublic, Id, PKey, PKeyRef, Private, Public};
+use openssl::rsa::Rsa;
+use openssl::stack::Stack;
+use openssl::x509::{CrlNumber, GeneralName, ReasonCode, X509, X509Crl, X509CrlRef, X509ExtensionRef, X509Ref, X509Req, X509ReqRef};
+use foreign_types::ForeignTypeRef;
+
+use crate::{Format, LoadError, RevocationReason, extension_nid, extension_value, general_name_strings, name_to_string, pkey_from_file};
+use crate::db::serial_to_hex;
+use crate::time::asn1_to_rfc3339;
+
+/// Anything `inspect` can read
+pub enum Object {
+    Certificate(X509),
+    Request(X509Req),
+    PrivateKey(PKey<Private>),
+    PublicKey(PKey<Public>),
+    Crl(X509Crl),
+}
+
+/// Label of the PEM block `bytes` start with, e.g. `CERTIFICATE`
+fn pem_label(bytes: &[u8]) -> Option<&str> {
+    let text = std::str::from_utf8(bytes).ok()?;
+    let text = text.trim_start().strip_prefix("-----BEGIN ")?;
+    let len = text.find("-----")?;
+
+    Some(&text[..len])
+}
+
+/// Read an object in PEM or DER, telling its type from the PEM label or by trying each DER decoder.
+///
+/// `passphrase` is only used for encrypted private keys.
+pub fn read_object(bytes: &[u8], passphrase: Option<&[u8]>) -> Result<Object, LoadError> {
+    if let Some(label) = pem_label(bytes) {
+        return Ok(match label {
+            "CERTIFICATE" => Object::Certificate(X509::from_pem(bytes)?),
+            "CERTIFICATE REQUEST" | "NEW CERTIFICATE REQUEST" => Object::Request(X509Req::from_pem(bytes)?),
+            "X509 CRL" => Object::Crl(X509Crl::from_pem(bytes)?),
+            "PUBLIC KEY" => Object::PublicKey(PKey::public_key_from_pem(bytes)?),
+            "RSA PUBLIC KEY" => Object::PublicKey(PKey::from_rsa(Rsa::public_key_from_pem_pkcs1(bytes)?)?),
+            x if x.ends_with("PRIVATE KEY") => Object::PrivateKey(pkey_from_file(&mut &bytes[..], passphrase, Format::Pem)?),
+            _ => return Err(LoadError::UnknownFormat),
+        });
+    }
+
+    if let Ok(x) = X509::from_der(bytes) {
+        Ok(Object::Certificate(x))
+    } else if let Ok(x) = X509Req::from_der(bytes) {
+        Ok(Object::Request(x))
+    } else if let Ok(x) = X509Crl::from_der(bytes) {
+        Ok(Object::Crl(x))
+    } else {
+        match pkey_from_file(&mut &bytes[..], passphrase, Format::Der) {
+            Ok(x) => Ok(Object::PrivateKey(x)),
+            Err(err @ LoadError::PassphraseRequired) | Err(err @ LoadError::WrongPassphrase) => Err(err),
+            Err(_) => match PKey::public_key_from_der(bytes) {
+                Ok(x) => Ok(Object::PublicKey(x)),
+                Err(_) => Err(LoadError::UnknownFormat),
+            },
+        }
+    }
+}
+
+/// Value of a report field
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Number(i64),
+    Bool(bool),
+    List(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Field {
+    /// Name of the field in JSON output
+    pub key: &'static str,
+    /// Name of the field in text output
+    pub label: &'static str,
+    pub value: Value,
+}
+
+/// Ordered description of an object
+#[derive(Debug, Default)]
+pub struct Report {
+    pub fields: Vec<Field>,
+}
+
+fn json_string(x: &str) -> String {
+    let mut res = String::from("\"");
+
+    for c in x.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+
+    res.push('"');
+    res
+}
+
+impl Report {
+    fn push(&mut self, key: &'static str, label: &'static str, value: Value) {
+        self.fields.push(Field { key, label, value });
+    }
+
+    fn text<T: Into<String>>(&mut self, key: &'static str, label: &'static str, value: T) {
+        self.push(key, label, Value::Text(value.into()));
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.fields.iter().find(|x| x.key == key).map(|x| &x.value)
+    }
+
+    /// One `Label: value` line per value, list fields repeat their label
+    pub fn to_text(&self) -> String {
+        let mut res = String::new();
+
+        for field in &self.fields {
+            let label = format!("{}:", field.label);
+
+            let values = match &field.value {
+                Value::Text(x) => vec![x.clone()],
+                Value::Number(x) => vec![x.to_string()],
+                Value::Bool(x) => vec![x.to_string()],
+                Value::List(x) => x.clone(),
+            };
+
+            for value in values {
+                res.push_str(&format!("{:<22}{}\n", label, value));
+            }
+        }
+
+        res
+    }
+
+    /// A single JSON object keyed by field names
+    pub fn to_json(&self) -> String {
+        let fields = self.fields.iter()
+            .map(|field| {
+                let value = match &field.value {
+                    Value::Text(x) => json_string(x),
+                    Value::Number(x) => x.to_string(),
+                    Value::Bool(x) => x.to_string(),
+                    Value::List(x) => format!("[{}]", x.iter().map(|x| json_string(x)).collect::<Vec<_>>().join(", ")),
+                };
+
+                format!("  {}: {}", json_string(field.key), value)
+            })
+            .collect::<Vec<_>>();
+
+        format!("{{\n{}\n}}\n", fields.join(",\n"))
+    }
+}
+
+fn hex_colons(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02X}", x)).collect::<Vec<_>>().join(":")
+}
+
+fn nid_name(nid: Nid) -> String {
+    nid.short_name().map(|x| x.to_string()).unwrap_or_else(|_| format!("{:?}", nid))
+}
+
+const KEY_USAGES: [&str; 9] = [
+    "digitalSignature",
+    "nonRepudiation",
+    "keyEncipherment",
+    "dataEncipherment",
+    "keyAgreement",
+    "keyCertSign",
+    "cRLSign",
+    "encipherOnly",
+    "decipherOnly",
+];
+
+fn cert_extensions(cert: &X509Ref) -> Vec<&X509ExtensionRef> {
+    unsafe {
+        (0..openssl_sys::X509_get_ext_count(cert.as_ptr()))
+            .map(|idx| X509ExtensionRef::from_ptr(openssl_sys::X509_get_ext(cert.as_ptr(), idx)))
+            .collect()
+    }
+}
+
+fn describe_extensions(report: &mut Report, exts: &[&X509ExtensionRef]) {
+    let mut names = Vec::<String>::default();
+    let mut sans = Vec::<String>::default();
+    let mut key_usage = Vec::<String>::default();
+    let mut ext_key_usage = Vec::<String>::default();
+
+    for ext in exts {
+        let nid = extension_nid(ext);
+        let critical = unsafe { openssl_sys::X509_EXTENSION_get_critical(ext.as_ptr()) > 0 };
+
+        names.push(if critical { format!("{} (critical)", nid_name(nid)) } else { nid_name(nid) });
+
+        match nid {
+            Nid::SUBJECT_ALT_NAME => {
+                if let Some(x) = unsafe { extension_value::<Stack<GeneralName>>(ext) } {
+                    sans.extend(general_name_strings(&x));
+                }
+            }
+            Nid::KEY_USAGE => {
+                if let Some(x) = unsafe { extension_value::<Asn1BitString>(ext) } {
+                    let bits = x.as_slice();
+
+                    for (idx, name) in KEY_USAGES.iter().enumerate() {
+                        if bits.get(idx / 8).is_some_and(|x| x & (0x80 >> (idx % 8)) != 0) {
+                            key_usage.push(name.to_string());
+                        }
+                    }
+                }
+            }
+            Nid::EXT_KEY_USAGE => {
+                if let Some(x) = unsafe { extension_value::<Stack<Asn1Object>>(ext) } {
+                    for object in &x {
+                        ext_key_usage.push(match object.nid() {
+                            Nid::UNDEF => object.to_string(),
+                            nid => nid_name(nid),
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    report.push("san", "SAN", Value::List(sans));
+    report.push("key_usage", "Key Usage", Value::List(key_usage));
+    report.push("extended_key_usage", "Extended Key Usage", Value::List(ext_key_usage));
+    report.push("extensions", "Extension", Value::List(names));
+}
+
+fn describe_key<T: HasPublic>(report: &mut Report, pkey: &PKeyRef<T>) -> Result<(), LoadError> {
+    let algorithm = match pkey.id() {
+        Id::RSA => "RSA",
+        Id::EC => "EC",
+        Id::ED25519 => "Ed25519",
+        Id::ED448 => "Ed448",
+        Id::DSA => "DSA",
+        _ => "unknown",
+    };
+
+    report.text("key_algorithm", "Key Algorithm", algorithm);
+    report.push("key_bits", "Key Bits", Value::Number(i64::from(pkey.bits())));
+
+    if pkey.id() == Id::EC {
+        if let Some(curve) = pkey.ec_key()?.group().curve_name() {
+            report.text("key_curve", "Key Curve", nid_name(curve));
+        }
+    }
+
+    if pkey.id() == Id::RSA {
+        if let Ok(exponent) = pkey.rsa()?.e().to_dec_str()?.parse() {
+            report.push("key_exponent", "Key Exponent", Value::Number(exponent));
+        }
+    }
+
+    let spki = hash(MessageDigest::sha256(), &pkey.public_key_to_der()?)?;
+    report.text("key_sha256", "Public Key SHA-256", hex_colons(&spki));
+
+    Ok(())
+}
+
+fn describe_cert(report: &mut Report, cert: &X509Ref) -> Result<(), LoadError> {
+    report.text("type", "Type", "certificate");
+    report.push("version", "Version", Value::Number(i64::from(cert.version()) + 1));
+    report.text("serial", "Serial", serial_to_hex(cert.serial_number().to_bn()?.as_ref())?);
+    report.text("subject", "Subject", name_to_string(cert.subject_name()));
+    report.text("issuer", "Issuer", name_to_string(cert.issuer_name()));
+    report.text("not_before", "Not Before", asn1_to_rfc3339(cert.not_before())?);
+    report.text("not_after", "Not After", asn1_to_rfc3339(cert.not_after())?);
+
+    let flags = unsafe { openssl_sys::X509_get_extension_flags(cert.as_ptr()) };
+    report.push("ca", "CA", Value::Bool(flags & openssl_sys::EXFLAG_CA != 0));
+
+    if let Some(pathlen) = cert.pathlen() {
+        report.push("pathlen", "Path Length", Value::Number(i64::from(pathlen)));
+    }
+
+    describe_extensions(report, &cert_extensions(cert));
+
+    report.text("signature_algorithm", "Signature Algorithm", nid_name(cert.signature_algorithm().object().nid()));
+    let pubkey = cert.public_key()?;
+    describe_key(report, &pubkey)?;
+
+    report.text("sha1_fingerprint", "SHA-1 Fingerprint", hex_colons(&cert.digest(MessageDigest::sha1())?));
+    report.text("sha256_fingerprint", "SHA-256 Fingerprint", hex_colons(&cert.digest(MessageDigest::sha256())?));
+
+    Ok(())
+}
+
+fn describe_req(report: &mut Report, req: &X509ReqRef) -> Result<(), LoadError> {
+    report.text("type", "Type", "certificate request");
+    report.text("subject", "Subject", name_to_string(req.subject_name()));
+
+    let exts = req.extensions().or_else(|_| Stack::new())?;
+    describe_extensions(report, &exts.iter().collect::<Vec<_>>());
+
+    let pubkey = req.public_key()?;
+    report.push("signature_valid", "Signature Valid", Value::Bool(req.verify(&pubkey)?));
+    describe_key(report, &pubkey)?;
+
+    report.text("sha256_fingerprint", "SHA-256 Fingerprint", hex_colons(&hash(MessageDigest::sha256(), &req.to_der()?)?));
+
+    Ok(())
+}
+
+fn describe_crl(report: &mut Report, crl: &X509CrlRef) -> Result<(), LoadError> {
+    report.text("type", "Type", "crl");
+    report.text("issuer", "Issuer", name_to_string(crl.issuer_name()));
+    report.text("last_update", "Last Update", asn1_to_rfc3339(crl.last_update())?);
+
+    if let Some(next_update) = crl.next_update() {
+        report.text("next_update", "Next Update", asn1_to_rfc3339(next_update)?);
+    }
+
+    if let Some((_, number)) = crl.extension::<CrlNumber>()? {
+        report.text("crl_number", "CRL Number", serial_to_hex(number.to_bn()?.as_ref())?);
+    }
+
+    let mut revoked = Vec::<String>::default();
+
+    for entry in crl.get_revoked().into_iter().flatten() {
+        let mut line = format!(
+            "{} {}",
+            serial_to_hex(entry.serial_number().to_bn()?.as_ref())?,
+            asn1_to_rfc3339(entry.revocation_date())?,
+        );
+
+        if let Some((_, code)) = entry.extension::<ReasonCode>()? {
+            match RevocationReason::from_code(code.get_i64()?) {
+                Some(reason) => line.push_str(&format!(" {}", reason.name())),
+                None => line.push_str(&format!(" reason {}", code.get_i64()?)),
+            }
+        }
+
+        revoked.push(line);
+    }
+
+    report.push("revoked", "Revoked", Value::List(revoked));
+    report.text("sha256_fingerprint", "SHA-256 Fingerprint", hex_colons(&hash(MessageDigest::sha256(), &crl.to_der()?)?));
+
+    Ok(())
+}
+
+/// Describe an object read by `read_object`
+pub fn describe(object: &Object) -> Result<Report, LoadError> {
+    let mut report = Report::default();
+
+    match object {
+        Object::Certificate(x) => describe_cert(&mut report, x)?,
+        Object::Request(x) => describe_req(&mut report, x)?,
+        Object::Crl(x) => describe_crl(&mut report, x)?,
+        Object::PrivateKey(x) => {
+            report.text("type", "Type", "private key");
+            describe_key(&mut report, x)?;
+        }
+        Object::PublicKey(x) => {
+            report.text("type", "Type", "public key");
+            describe_key(&mut report, x)?;
+        }
+    }
+
+    Ok(report)
+}
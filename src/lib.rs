@@ -1,54 +1,217 @@
 #[cfg(test)]
 mod tests;
 
-use openssl::asn1::Asn1Time;
+use openssl::asn1::{Asn1Integer, Asn1Object, Asn1OctetString, Asn1StringRef, Asn1Time, Asn1TimeRef};
 use openssl::error::ErrorStack;
-use openssl::pkey::{PKey, PKeyRef, Private, Public};
+use openssl::pkey::{HasPublic, Id, PKey, PKeyRef, Private, Public};
 use openssl::rsa::Rsa;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::nid::Nid;
 use openssl::x509::*;
 use openssl::x509::extension::*;
-use openssl::bn::{BigNum, MsbOption};
+use openssl::bn::{BigNum, BigNumRef, MsbOption};
 use openssl::hash::MessageDigest;
-use std::io::{Read, Write, Error as IOError};
-use openssl::stack::Stack;
+use openssl::symm::Cipher;
+use std::io::{Read, Write, Error as IOError, ErrorKind};
+use std::cell::Cell;
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use openssl::stack::{Stack, StackRef};
+use openssl::pkcs12::Pkcs12;
+use foreign_types::{ForeignType, ForeignTypeRef};
+use libc::c_int;
+
+use crate::constraints::NameConstraints;
+use crate::policy::{IssuerUrls, Policy};
 
 
 pub mod args;
+pub mod constraints;
+pub mod db;
+pub mod der;
+pub mod inspect;
+pub mod output;
+pub mod passphrase;
+pub mod policy;
+pub mod time;
+pub mod verify;
+
+/// Optional notBefore and notAfter of a certificate
+pub type Validity = (Option<Asn1Time>, Option<Asn1Time>);
+
+/// Whether notBefore, or the current time when it is unset, comes before notAfter
+pub fn validity_is_ordered(not_before_after: &Validity) -> Result<bool, ErrorStack> {
+    match not_before_after {
+        (_, None) => Ok(true),
+        (not_before, Some(not_after)) => {
+            let not_before = match not_before {
+                Some(x) => time::asn1_to_unix(x)?,
+                None => time::asn1_to_unix(&*Asn1Time::days_from_now(0)?)?,
+            };
+
+            Ok(not_before < time::asn1_to_unix(not_after)?)
+        }
+    }
+}
+
+/// Move a notAfter later than the issuing CA's own notAfter back to it.
+///
+/// Returns whether the validity was shortened.
+pub fn clamp_not_after(ca_cert: &X509Ref, not_before_after: &mut Validity) -> Result<bool, ErrorStack> {
+    let ca_not_after = time::asn1_to_unix(ca_cert.not_after())?;
+
+    let clamp = match &not_before_after.1 {
+        Some(not_after) => time::asn1_to_unix(not_after)? > ca_not_after,
+        None => false,
+    };
+
+    if clamp {
+        not_before_after.1 = Some(Asn1Time::from_unix(ca_not_after)?);
+    }
+
+    Ok(clamp)
+}
+
+/// Named elliptic curves supported for ECDSA keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcCurve {
+    P256,
+    P384,
+}
 
+impl EcCurve {
+    pub fn nid(self) -> Nid {
+        match self {
+            EcCurve::P256 => Nid::X9_62_PRIME256V1,
+            EcCurve::P384 => Nid::SECP384R1,
+        }
+    }
+}
 
-pub fn build_privkey() -> Result<PKey<Private>, ErrorStack> {
-    let rsa = Rsa::generate(2048)?;
-    let privkey = PKey::from_rsa(rsa)?;
+/// Algorithm and size of a private key generated by `build_privkey`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySpec {
+    Rsa(u32),
+    Ec(EcCurve),
+    Ed25519,
+}
+
+impl Default for KeySpec {
+    fn default() -> Self { KeySpec::Rsa(2048) }
+}
+
+impl KeySpec {
+    /// The type of the keys generated for this spec
+    pub fn id(&self) -> Id {
+        match self {
+            KeySpec::Rsa(_) => Id::RSA,
+            KeySpec::Ec(_) => Id::EC,
+            KeySpec::Ed25519 => Id::ED25519,
+        }
+    }
+}
+
+pub fn build_privkey(spec: &KeySpec) -> Result<PKey<Private>, ErrorStack> {
+    let privkey = match spec {
+        KeySpec::Rsa(bits) => {
+            let rsa = Rsa::generate(*bits)?;
+            PKey::from_rsa(rsa)?
+        }
+        KeySpec::Ec(curve) => {
+            let group = EcGroup::from_curve_name(curve.nid())?;
+            PKey::from_ec_key(EcKey::generate(&group)?)?
+        }
+        KeySpec::Ed25519 => PKey::generate_ed25519()?,
+    };
 
     Ok(privkey)
 }
 
+/// Pick the digest used when signing with the given key.
+///
+/// Ed25519 hashes the message itself and must be used with a null digest,
+/// ECDSA keys get a digest matching the strength of their curve.
+pub fn digest_for_key<T: HasPublic>(pkey: &PKeyRef<T>) -> MessageDigest {
+    match pkey.id() {
+        Id::ED25519 | Id::ED448 => MessageDigest::null(),
+        Id::EC if pkey.bits() > 384 => MessageDigest::sha512(),
+        Id::EC if pkey.bits() > 256 => MessageDigest::sha384(),
+        _ => MessageDigest::sha256(),
+    }
+}
+
+/// The type of an extension
+pub fn extension_nid(ext: &X509ExtensionRef) -> Nid {
+    unsafe {
+        let object = openssl_sys::X509_EXTENSION_get_object(ext.as_ptr());
+        Nid::from_raw(openssl_sys::OBJ_obj2nid(object))
+    }
+}
+
+/// DER value of the first extension of a certificate with the given type
+pub fn cert_extension_data(cert: &X509Ref, nid: Nid) -> Option<&[u8]> {
+    unsafe {
+        let idx = openssl_sys::X509_get_ext_by_NID(cert.as_ptr(), nid.as_raw(), -1);
+
+        if idx < 0 {
+            return None;
+        }
+
+        let ext = openssl_sys::X509_get_ext(cert.as_ptr(), idx);
+        let data = openssl_sys::X509_EXTENSION_get_data(ext);
+
+        Some(Asn1StringRef::from_ptr(data as *mut _).as_slice())
+    }
+}
+
+/// Decode the value of an extension.
+///
+/// `T` must be the OpenSSL type the extension decodes to, e.g. `Stack<GeneralName>` for a
+/// subjectAltName.
+unsafe fn extension_value<T: ForeignType>(ext: &X509ExtensionRef) -> Option<T> {
+    let value = openssl_sys::X509V3_EXT_d2i(ext.as_ptr());
+
+    if value.is_null() {
+        None
+    } else {
+        Some(T::from_ptr(value as *mut _))
+    }
+}
+
+/// Generate a random positive 159-bit certificate serial number
+fn random_serial() -> Result<Asn1Integer, ErrorStack> {
+    let mut serial = BigNum::new()?;
+    serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
+    serial.to_asn1_integer()
+}
+
+/// Make a self-signed root CA certificate, restricted to `name_constraints` when given
 pub fn build_ca_cert(
     privkey: &PKey<Private>,
     x509_name: &X509Name,
-    not_before_after: &(Option<Asn1Time>, Option<Asn1Time>),
+    name_constraints: Option<&NameConstraints>,
+    not_before_after: &Validity,
 ) -> Result<X509, ErrorStack> {
     let mut cert_builder = X509::builder()?;
     cert_builder.set_version(2)?;
-    let serial_number = {
-        let mut serial = BigNum::new()?;
-        serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
-        serial.to_asn1_integer()?
-    };
+    let serial_number = random_serial()?;
     cert_builder.set_serial_number(&serial_number)?;
-    cert_builder.set_subject_name(&x509_name)?;
-    cert_builder.set_issuer_name(&x509_name)?;
-    cert_builder.set_pubkey(&privkey)?;
+    cert_builder.set_subject_name(x509_name)?;
+    cert_builder.set_issuer_name(x509_name)?;
+    cert_builder.set_pubkey(privkey)?;
 
     let (not_before, not_after) = not_before_after;
 
     if let Some(not_before) = not_before {
-        cert_builder.set_not_before(&not_before)?;
+        cert_builder.set_not_before(not_before)?;
     }
 
     if let Some(not_after) = not_after {
-        cert_builder.set_not_after(&not_after)?;
-     }
+        cert_builder.set_not_after(not_after)?;
+    }
 
     cert_builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
     cert_builder.append_extension(KeyUsage::new()
@@ -57,16 +220,182 @@ pub fn build_ca_cert(
         .crl_sign()
         .build()?)?;
 
+    if let Some(name_constraints) = name_constraints.filter(|x| !x.is_empty()) {
+        cert_builder.append_extension(name_constraints.to_extension()?)?;
+    }
+
     let subject_key_identifier =
         SubjectKeyIdentifier::new().build(&cert_builder.x509v3_context(None, None))?;
     cert_builder.append_extension(subject_key_identifier)?;
 
-    cert_builder.sign(&privkey, MessageDigest::sha256())?;
+    cert_builder.sign(privkey, digest_for_key(privkey))?;
     let cert = cert_builder.build();
 
     Ok(cert)
 }
 
+/// Attributes of one relative distinguished name as (type, value) pairs, each type given by its
+/// short name, long name or OID
+pub type Rdn = Vec<(String, String)>;
+
+#[derive(Debug)]
+pub enum NameError {
+    OpenSSL(ErrorStack),
+    /// An attribute type containing a NUL byte, which OpenSSL cannot look up
+    InvalidType(String),
+}
+
+impl From<ErrorStack> for NameError {
+    fn from(x: ErrorStack) -> Self { NameError::OpenSSL(x) }
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NameError::OpenSSL(_) => write!(f, "OpenSSL error"),
+            NameError::InvalidType(x) => write!(f, "unknown attribute type {:?}", x),
+        }
+    }
+}
+
+impl Error for NameError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NameError::OpenSSL(err) => Some(err),
+            NameError::InvalidType(_) => None,
+        }
+    }
+}
+
+/// Build a distinguished name from its RDNs, most significant first.
+///
+/// An RDN with several attributes becomes a multi-valued RDN such as `OU=Web+OU=Ops`.
+pub fn build_name(rdns: &[Rdn]) -> Result<X509Name, NameError> {
+    let name = X509NameBuilder::new()?.build();
+
+    for rdn in rdns {
+        for (i, (field, value)) in rdn.iter().enumerate() {
+            let field = CString::new(field.as_str()).map_err(|_| NameError::InvalidType(field.clone()))?;
+            // set 0 starts a new RDN, -1 adds to the last one
+            let set = if i == 0 { 0 } else { -1 };
+
+            unsafe {
+                if openssl_sys::X509_NAME_add_entry_by_txt(
+                    name.as_ptr(),
+                    field.as_ptr(),
+                    openssl_sys::MBSTRING_UTF8,
+                    value.as_ptr(),
+                    value.len() as c_int,
+                    -1,
+                    set,
+                ) <= 0 {
+                    return Err(ErrorStack::get().into());
+                }
+            }
+        }
+    }
+
+    Ok(name)
+}
+
+/// Render a name in the `/CN=x/O=y` form used by OpenSSL
+pub fn name_to_string(name: &X509NameRef) -> String {
+    let mut res = String::new();
+
+    for entry in name.entries() {
+        let field = entry.object().nid().short_name().unwrap_or("UNDEF");
+        let value = entry.data().to_string().unwrap_or_default();
+
+        res.push('/');
+        res.push_str(field);
+        res.push('=');
+        res.push_str(&value);
+    }
+
+    res
+}
+
+/// Render the subjectAltName entries of a certificate as `DNS:x`, `IP:x`, `email:x` and `URI:x`
+pub fn san_strings(cert: &X509Ref) -> Vec<String> {
+    match cert.subject_alt_names() {
+        Some(names) => general_name_strings(&names),
+        None => Vec::default(),
+    }
+}
+
+/// Render general names the way `san_strings` does
+pub fn general_name_strings(names: &StackRef<GeneralName>) -> Vec<String> {
+    let mut res = Vec::<String>::default();
+
+    for name in names {
+        if let Some(x) = name.dnsname() {
+            res.push(format!("DNS:{}", x));
+        } else if let Some(x) = name.ipaddress() {
+            let ip = match x.len() {
+                4 => IpAddr::from(<[u8; 4]>::try_from(x).unwrap()).to_string(),
+                16 => IpAddr::from(<[u8; 16]>::try_from(x).unwrap()).to_string(),
+                _ => format!("{:02X?}", x),
+            };
+            res.push(format!("IP:{}", ip));
+        } else if let Some(x) = name.email() {
+            res.push(format!("email:{}", x));
+        } else if let Some(x) = name.uri() {
+            res.push(format!("URI:{}", x));
+        } else if let Some(x) = name.directory_name() {
+            res.push(format!("DirName:{}", name_to_string(x)));
+        } else if let Some(x) = general_name_upn(name) {
+            res.push(format!("UPN:{}", x));
+        } else {
+            res.push("othername:<unsupported>".to_string());
+        }
+    }
+
+    res
+}
+
+/// User principal name carried by an otherName, `None` for any other general name
+pub fn general_name_upn(name: &GeneralNameRef) -> Option<String> {
+    extern "C" {
+        fn i2d_GENERAL_NAME(name: *mut openssl_sys::GENERAL_NAME, out: *mut *mut u8) -> c_int;
+    }
+
+    let der = unsafe {
+        let len = i2d_GENERAL_NAME(name.as_ptr(), std::ptr::null_mut());
+
+        if len <= 0 {
+            return None;
+        }
+
+        let mut buf = vec![0; len as usize];
+        let mut out = buf.as_mut_ptr();
+        i2d_GENERAL_NAME(name.as_ptr(), &mut out);
+        buf
+    };
+
+    // otherName [0] { type-id OBJECT IDENTIFIER, value [0] EXPLICIT UTF8String }
+    let (tag, content, _) = der::read_tlv(&der)?;
+    if tag != der::TAG_OTHER_NAME {
+        return None;
+    }
+
+    let (tag, oid, rest) = der::read_tlv(content)?;
+    if tag != der::TAG_OID || oid != Asn1Object::from_str(args::UPN_OID).ok()?.as_slice() {
+        return None;
+    }
+
+    let (tag, value, _) = der::read_tlv(rest)?;
+    if tag != der::TAG_OTHER_NAME {
+        return None;
+    }
+
+    let (tag, value, _) = der::read_tlv(value)?;
+    if tag != der::TAG_UTF8_STRING {
+        return None;
+    }
+
+    String::from_utf8(value.to_vec()).ok()
+}
+
 /// Make a X509 request with the given private key
 pub fn build_ca_req<F>(
     privkey: &PKey<Private>,
@@ -75,9 +404,9 @@ pub fn build_ca_req<F>(
 ) -> Result<X509Req, ErrorStack>
     where F: FnOnce(&mut X509ReqBuilder) -> Result<(), ErrorStack> {
     let mut req_builder = X509ReqBuilder::new()?;
-    req_builder.set_pubkey(&privkey)?;
+    req_builder.set_pubkey(privkey)?;
 
-    req_builder.set_subject_name(&x509_name)?;
+    req_builder.set_subject_name(x509_name)?;
 
     //let mut extensions = Stack::<X509Extension>::new()?;
 
@@ -85,79 +414,487 @@ pub fn build_ca_req<F>(
 
     //req_builder.add_extensions(&extensions)?;
 
-    req_builder.sign(&privkey, MessageDigest::sha256())?;
+    req_builder.sign(privkey, digest_for_key(privkey))?;
     let req = req_builder.build();
     Ok(req)
 }
 
+/// Public key placed into a certificate signed from a request
+#[derive(Clone, Copy)]
+pub enum SubjectKey<'a> {
+    /// The key the request was signed with
+    FromRequest,
+    /// A key that must be the same as the one in the request
+    Expect(&'a PKeyRef<Public>),
+    /// A key that replaces the one in the request
+    Override(&'a PKeyRef<Public>),
+}
+
+#[derive(Debug)]
+pub enum SignError {
+    OpenSSL(ErrorStack),
+    /// The request is not signed by the key it carries
+    BadRequestSignature,
+    /// The expected public key differs from the one in the request
+    KeyMismatch,
+    /// The request violates the signing policy of the CA
+    Policy(Vec<String>),
+    /// notBefore does not come before notAfter
+    InvalidValidity,
+    /// The names of the request fall outside the name constraints of the CA certificate
+    NameConstraints(Vec<String>),
+}
+
+impl From<ErrorStack> for SignError {
+    fn from(x: ErrorStack) -> Self { SignError::OpenSSL(x) }
+}
+
+impl fmt::Display for SignError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignError::OpenSSL(_) => write!(f, "OpenSSL error"),
+            SignError::BadRequestSignature => write!(f, "the request is not signed by the key it contains"),
+            SignError::KeyMismatch => write!(f, "the request key differs from the expected public key"),
+            SignError::Policy(violations) => write!(f, "the request violates the CA policy: {}", violations.join("; ")),
+            SignError::InvalidValidity => write!(f, "the certificate would expire before it becomes valid"),
+            SignError::NameConstraints(violations) => write!(f, "the request is outside the name constraints of the CA: {}", violations.join("; ")),
+        }
+    }
+}
+
+impl Error for SignError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SignError::OpenSSL(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Check the self-signature of a request and pick the public key to certify
+fn request_pubkey(req: &X509ReqRef, subject_key: SubjectKey) -> Result<PKey<Public>, SignError> {
+    let req_pubkey = req.public_key()?;
+
+    if !req.verify(&req_pubkey)? {
+        return Err(SignError::BadRequestSignature);
+    }
+
+    match subject_key {
+        SubjectKey::FromRequest => Ok(req_pubkey),
+        SubjectKey::Expect(x) if x.public_eq(&req_pubkey) => Ok(req_pubkey),
+        SubjectKey::Expect(_) => Err(SignError::KeyMismatch),
+        SubjectKey::Override(x) => Ok(x.to_owned()),
+    }
+}
+
+/// Check the names a certificate of `ca_cert` would carry, and the permitted subtrees of a
+/// subordinate CA, against the name constraints of the CA, returning those constraints
+fn check_name_constraints(
+    ca_cert: &X509Ref,
+    subject: &X509NameRef,
+    sans: Option<&StackRef<GeneralName>>,
+    subordinate: Option<&NameConstraints>,
+) -> Result<Option<NameConstraints>, SignError> {
+    let der = match cert_extension_data(ca_cert, Nid::NAME_CONSTRAINTS) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    let constraints = match NameConstraints::from_der(der) {
+        Some(x) => x,
+        None => return Err(SignError::NameConstraints(vec!["the name constraints of the CA could not be decoded".to_string()])),
+    };
+
+    let mut violations = constraints.check(subject, sans);
+    violations.extend(subordinate.map(|x| constraints.check_subordinate(x)).unwrap_or_default());
+
+    if violations.is_empty() {
+        Ok(Some(constraints))
+    } else {
+        Err(SignError::NameConstraints(violations))
+    }
+}
+
+/// Add the authorityInfoAccess and cRLDistributionPoints extensions for `urls`, except those `skip`
+/// says the certificate gets already
+fn append_issuer_urls<F: Fn(Nid) -> bool>(cert_builder: &mut X509Builder, urls: &IssuerUrls, skip: F) -> Result<(), ErrorStack> {
+    if !skip(Nid::INFO_ACCESS) {
+        if let Some(ext) = urls.authority_info_access()? {
+            cert_builder.append_extension(ext)?;
+        }
+    }
+
+    if !skip(Nid::CRL_DISTRIBUTION_POINTS) {
+        if let Some(ext) = urls.crl_distribution_points()? {
+            cert_builder.append_extension(ext)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sign a request as an end-entity certificate of `ca_cert`.
+///
+/// Request extensions are copied, dropped or rejected as `policy` says. Copied extensions
+/// replace the basic constraints, key usage and key identifiers the CA would set itself.
+/// The subject and copied subjectAltName must fall within the name constraints of `ca_cert`, and
+/// the certificate points at the issuer URLs of `policy`.
 pub fn build_ca_signed_cert<F>(
     ca_cert: &X509Ref,
     ca_privkey: &PKeyRef<Private>,
-    pubkey: &PKey<Public>,
     req: &X509Req,
-    not_before_after: &(Option<Asn1Time>, Option<Asn1Time>),
+    subject_key: SubjectKey,
+    policy: &Policy,
+    not_before_after: &Validity,
     map: F,
-) -> Result<X509, ErrorStack>
+) -> Result<X509, SignError>
 where F: FnOnce(&mut X509Builder) -> Result<(), ErrorStack> {
+    if !validity_is_ordered(not_before_after)? {
+        return Err(SignError::InvalidValidity);
+    }
+
+    let pubkey = request_pubkey(req, subject_key)?;
+    let copied = policy.apply(req, not_before_after)?;
+    let requested = |nid: Nid| copied.iter().any(|ext| extension_nid(ext) == nid);
+
+    let sans = copied.iter()
+        .find(|ext| extension_nid(ext) == Nid::SUBJECT_ALT_NAME)
+        .and_then(|ext| unsafe { extension_value::<Stack<GeneralName>>(ext) });
+    check_name_constraints(ca_cert, req.subject_name(), sans.as_deref(), None)?;
+
     let mut cert_builder = X509::builder()?;
     cert_builder.set_version(2)?;
-    let serial_number = {
-        let mut serial = BigNum::new()?;
-        serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
-        serial.to_asn1_integer()?
+
+    let serial_number = random_serial()?;
+    cert_builder.set_serial_number(&serial_number)?;
+    cert_builder.set_subject_name(req.subject_name())?;
+    cert_builder.set_issuer_name(ca_cert.subject_name())?;
+    cert_builder.set_pubkey(&pubkey)?;
+
+    let (not_before, not_after) = not_before_after;
+
+    if let Some(not_before) = not_before {
+        cert_builder.set_not_before(not_before)?;
+    }
+
+    if let Some(not_after) = not_after {
+        cert_builder.set_not_after(not_after)?;
+    }
+
+    if !requested(Nid::BASIC_CONSTRAINTS) {
+        cert_builder.append_extension(BasicConstraints::new().build()?)?;
+    }
+
+    // requests made with key purposes carry the KeyUsage bits matching them
+    if !requested(Nid::KEY_USAGE) {
+        cert_builder.append_extension(KeyUsage::new()
+            .critical()
+            .non_repudiation()
+            .digital_signature()
+            .key_encipherment()
+            .build()?)?;
+    }
+
+    if !requested(Nid::SUBJECT_KEY_IDENTIFIER) {
+        let subject_key_identifier =
+            SubjectKeyIdentifier::new().build(&cert_builder.x509v3_context(Some(ca_cert), None))?;
+        cert_builder.append_extension(subject_key_identifier)?;
+    }
+
+    if !requested(Nid::AUTHORITY_KEY_IDENTIFIER) {
+        let auth_key_identifier = AuthorityKeyIdentifier::new()
+            .keyid(false)
+            .issuer(false)
+            .build(&cert_builder.x509v3_context(Some(ca_cert), None))?;
+        cert_builder.append_extension(auth_key_identifier)?;
+    }
+
+    append_issuer_urls(&mut cert_builder, &policy.urls, requested)?;
+
+    map(&mut cert_builder)?;
+
+    for ext in copied {
+        cert_builder.append_extension(ext)?;
+    }
+
+    cert_builder.sign(ca_privkey, digest_for_key(ca_privkey))?;
+    let cert = cert_builder.build();
+
+    Ok(cert)
+}
+
+/// Sign a request as a subordinate CA of `ca_cert`.
+///
+/// `pathlen` limits the number of intermediates that may follow the new CA in a chain and
+/// `name_constraints` the names it may issue certificates for, which must stay within those of
+/// `ca_cert`. The name constraints of `ca_cert` are copied for the name types `name_constraints`
+/// does not restrict. The certificate points at `issuer_urls` of `ca_cert`. Extensions carried by
+/// the request are not copied into the certificate.
+#[allow(clippy::too_many_arguments)]
+pub fn build_intermediate_cert<F>(
+    ca_cert: &X509Ref,
+    ca_privkey: &PKeyRef<Private>,
+    req: &X509Req,
+    pathlen: Option<u32>,
+    name_constraints: Option<&NameConstraints>,
+    issuer_urls: &IssuerUrls,
+    not_before_after: &Validity,
+    map: F,
+) -> Result<X509, SignError>
+where F: FnOnce(&mut X509Builder) -> Result<(), ErrorStack> {
+    if !validity_is_ordered(not_before_after)? {
+        return Err(SignError::InvalidValidity);
+    }
+
+    let pubkey = request_pubkey(req, SubjectKey::FromRequest)?;
+
+    // the subordinate carries the constraints of its issuer for the types it does not narrow
+    // itself, so that checking against the direct issuer covers the whole chain
+    let name_constraints = match check_name_constraints(ca_cert, req.subject_name(), None, name_constraints)? {
+        Some(issuer) => Some(name_constraints.cloned().unwrap_or_default().inherit(&issuer)),
+        None => name_constraints.cloned(),
     };
 
+    let mut cert_builder = X509::builder()?;
+    cert_builder.set_version(2)?;
+
+    let serial_number = random_serial()?;
     cert_builder.set_serial_number(&serial_number)?;
     cert_builder.set_subject_name(req.subject_name())?;
     cert_builder.set_issuer_name(ca_cert.subject_name())?;
-    cert_builder.set_pubkey(pubkey)?;
+    cert_builder.set_pubkey(&pubkey)?;
 
     let (not_before, not_after) = not_before_after;
 
     if let Some(not_before) = not_before {
-        cert_builder.set_not_before(&not_before)?;
+        cert_builder.set_not_before(not_before)?;
     }
 
     if let Some(not_after) = not_after {
-        cert_builder.set_not_after(&not_after)?;
+        cert_builder.set_not_after(not_after)?;
     }
 
-    cert_builder.append_extension(BasicConstraints::new().build()?)?;
+    let mut basic_constraints = BasicConstraints::new();
+    basic_constraints.critical().ca();
+
+    if let Some(pathlen) = pathlen {
+        basic_constraints.pathlen(pathlen);
+    }
 
+    cert_builder.append_extension(basic_constraints.build()?)?;
     cert_builder.append_extension(KeyUsage::new()
         .critical()
-        .non_repudiation()
-        .digital_signature()
-        .key_encipherment()
+        .key_cert_sign()
+        .crl_sign()
         .build()?)?;
 
+    if let Some(name_constraints) = name_constraints.filter(|x| !x.is_empty()) {
+        cert_builder.append_extension(name_constraints.to_extension()?)?;
+    }
+
     let subject_key_identifier =
         SubjectKeyIdentifier::new().build(&cert_builder.x509v3_context(Some(ca_cert), None))?;
     cert_builder.append_extension(subject_key_identifier)?;
 
     let auth_key_identifier = AuthorityKeyIdentifier::new()
-        .keyid(false)
-        .issuer(false)
+        .keyid(true)
         .build(&cert_builder.x509v3_context(Some(ca_cert), None))?;
     cert_builder.append_extension(auth_key_identifier)?;
 
-    map(&mut cert_builder)?;
+    append_issuer_urls(&mut cert_builder, issuer_urls, |_| false)?;
 
-    let d = req.extensions().or_else(|_| Stack::<_>::new())?;
-    for ext in d {
-        cert_builder.append_extension(ext)?;
-    }
+    map(&mut cert_builder)?;
 
-    cert_builder.sign(&ca_privkey, MessageDigest::sha256())?;
+    cert_builder.sign(ca_privkey, digest_for_key(ca_privkey))?;
     let cert = cert_builder.build();
 
     Ok(cert)
 }
 
+/// Generate a key and sign a certificate for it in one go.
+///
+/// `map` adds the extensions of the request, which then go through `policy` as in
+/// `build_ca_signed_cert`.
+pub fn build_issued_cert<F>(
+    ca_cert: &X509Ref,
+    ca_privkey: &PKeyRef<Private>,
+    spec: &KeySpec,
+    x509_name: &X509Name,
+    policy: &Policy,
+    not_before_after: &Validity,
+    map: F,
+) -> Result<(PKey<Private>, X509), SignError>
+where F: FnOnce(&mut X509ReqBuilder) -> Result<(), ErrorStack> {
+    let privkey = build_privkey(spec)?;
+    let req = build_ca_req(&privkey, x509_name, map)?;
+    let cert = build_ca_signed_cert(ca_cert, ca_privkey, &req, SubjectKey::FromRequest, policy, not_before_after, |_| Ok(()))?;
+
+    Ok((privkey, cert))
+}
+
+/// Reason a certificate was revoked, see RFC 5280 section 5.3.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RevocationReason {
+    Unspecified,
+    KeyCompromise,
+    CaCompromise,
+    AffiliationChanged,
+    Superseded,
+    CessationOfOperation,
+    CertificateHold,
+    RemoveFromCrl,
+    PrivilegeWithdrawn,
+    AaCompromise,
+}
+
+const REVOCATION_REASONS: [(RevocationReason, &str, u8); 10] = [
+    (RevocationReason::Unspecified, "unspecified", 0),
+    (RevocationReason::KeyCompromise, "keyCompromise", 1),
+    (RevocationReason::CaCompromise, "CACompromise", 2),
+    (RevocationReason::AffiliationChanged, "affiliationChanged", 3),
+    (RevocationReason::Superseded, "superseded", 4),
+    (RevocationReason::CessationOfOperation, "cessationOfOperation", 5),
+    (RevocationReason::CertificateHold, "certificateHold", 6),
+    (RevocationReason::RemoveFromCrl, "removeFromCRL", 8),
+    (RevocationReason::PrivilegeWithdrawn, "privilegeWithdrawn", 9),
+    (RevocationReason::AaCompromise, "AACompromise", 10),
+];
+
+impl RevocationReason {
+    /// Names of all reasons, spelled as OpenSSL does in its `index.txt`
+    pub fn names() -> Vec<&'static str> {
+        REVOCATION_REASONS.iter().map(|(_, name, _)| *name).collect()
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        REVOCATION_REASONS.iter()
+            .find(|(_, x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(reason, _, _)| *reason)
+    }
+
+    pub fn from_code(code: i64) -> Option<Self> {
+        REVOCATION_REASONS.iter()
+            .find(|(_, _, x)| i64::from(*x) == code)
+            .map(|(reason, _, _)| *reason)
+    }
+
+    pub fn name(self) -> &'static str {
+        REVOCATION_REASONS.iter().find(|(x, _, _)| *x == self).map(|(_, name, _)| *name).unwrap()
+    }
+
+    /// `CRLReason` value encoded into the CRL entry
+    pub fn code(self) -> u8 {
+        REVOCATION_REASONS.iter().find(|(x, _, _)| *x == self).map(|(_, _, code)| *code).unwrap()
+    }
+}
+
+/// An entry of a certificate revocation list
+pub struct RevokedCert {
+    pub serial: BigNum,
+    pub date: Asn1Time,
+    pub reason: Option<RevocationReason>,
+}
+
+fn append_revocation_reason(revoked: &X509RevokedRef, reason: RevocationReason) -> Result<(), ErrorStack> {
+    let oid = Asn1Object::from_str("2.5.29.21")?;
+    // CRLReason ::= ENUMERATED
+    let value = Asn1OctetString::new_from_bytes(&[0x0a, 0x01, reason.code()])?;
+    let extension = X509Extension::new_from_der(&oid, false, &value)?;
+
+    unsafe {
+        if openssl_sys::X509_REVOKED_add_ext(revoked.as_ptr(), extension.as_ptr(), -1) <= 0 {
+            return Err(ErrorStack::get());
+        }
+    }
+
+    Ok(())
+}
+
+/// Make a version 2 CRL of `ca_cert` listing the `revoked` certificates
+pub fn build_crl(
+    ca_cert: &X509Ref,
+    ca_privkey: &PKeyRef<Private>,
+    revoked: &[RevokedCert],
+    crl_number: &BigNumRef,
+    next_update: &Asn1TimeRef,
+) -> Result<X509Crl, ErrorStack> {
+    let mut crl_builder = X509CrlBuilder::new()?;
+    crl_builder.set_issuer_name(ca_cert.subject_name())?;
+    let last_update = Asn1Time::days_from_now(0)?;
+    crl_builder.set_last_update(&last_update)?;
+    crl_builder.set_next_update(next_update)?;
+
+    for entry in revoked {
+        let mut revoked_builder = X509RevokedBuilder::new()?;
+        let serial = entry.serial.to_asn1_integer()?;
+        revoked_builder.set_serial_number(&serial)?;
+        revoked_builder.set_revocation_date(&entry.date)?;
+        let revoked = revoked_builder.build();
+
+        if let Some(reason) = entry.reason {
+            append_revocation_reason(&revoked, reason)?;
+        }
+
+        crl_builder.add_revoked(revoked)?;
+    }
+
+    // the key identifier only depends on the issuer, any certificate context will do
+    let context_builder = X509::builder()?;
+    let auth_key_identifier = AuthorityKeyIdentifier::new()
+        .keyid(true)
+        .build(&context_builder.x509v3_context(Some(ca_cert), None))?;
+    crl_builder.append_extension(auth_key_identifier)?;
+    crl_builder.append_extension(CrlNumber::new(crl_number.to_owned()?)?.build()?)?;
+
+    crl_builder.sort()?;
+    crl_builder.sign(ca_privkey, digest_for_key(ca_privkey))?;
+
+    crl_builder.build()
+}
+
+/// Encoding of keys, certificates, requests and CRLs in files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// Tell PEM from DER when reading, write PEM
+    #[default]
+    Auto,
+    Pem,
+    Der,
+}
+
+impl Format {
+    /// Resolve `Auto` to the format `bytes` are in, PEM when they start with a PEM header
+    fn detect(self, bytes: &[u8]) -> Format {
+        match self {
+            Format::Auto if bytes.trim_ascii_start().starts_with(b"-----BEGIN ") => Format::Pem,
+            Format::Auto => Format::Der,
+            x => x,
+        }
+    }
+
+    fn is_der(self) -> bool {
+        self == Format::Der
+    }
+}
+
 #[derive(Debug)]
 pub enum LoadError {
     IO(IOError),
     OpenSSL(ErrorStack),
+    /// The private key is encrypted, but no passphrase was given
+    PassphraseRequired,
+    /// The private key could not be decrypted with the given passphrase
+    WrongPassphrase,
+    /// The CA database is malformed or the requested change is not possible
+    Database(String),
+    /// The CA configuration file is malformed
+    Config(String),
+    /// The input is not a certificate, request, key or CRL in PEM or DER
+    UnknownFormat,
+    /// The private key does not belong to the certificate
+    KeyMismatch,
+    /// The input could not be decoded as the named kind of object
+    WrongFormat(&'static str, ErrorStack),
 }
 
 impl From<IOError> for LoadError {
@@ -168,57 +905,272 @@ impl From<ErrorStack> for LoadError {
     fn from(x: ErrorStack) -> Self { LoadError::OpenSSL(x) }
 }
 
-pub fn pkey_from_file(file: &mut Read) -> Result<PKey<Private>, LoadError> {
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::IO(_) => write!(f, "I/O error"),
+            LoadError::OpenSSL(_) => write!(f, "OpenSSL error"),
+            LoadError::PassphraseRequired => write!(f, "the private key is encrypted and no passphrase was given"),
+            LoadError::WrongPassphrase => write!(f, "wrong passphrase"),
+            LoadError::Database(msg) => write!(f, "ca database: {}", msg),
+            LoadError::Config(msg) => write!(f, "ca configuration: {}", msg),
+            LoadError::UnknownFormat => write!(f, "not a certificate, request, key or crl"),
+            LoadError::KeyMismatch => write!(f, "the private key does not belong to the certificate"),
+            LoadError::WrongFormat(expected, _) => write!(f, "not a valid {}", expected),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::IO(err) => Some(err),
+            LoadError::OpenSSL(err) | LoadError::WrongFormat(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Map a decoding failure to `LoadError::WrongFormat`
+fn wrong_format(expected: &'static str) -> impl Fn(ErrorStack) -> LoadError {
+    move |err| LoadError::WrongFormat(expected, err)
+}
+
+/// Load a private key, decrypting it with `passphrase` if it is encrypted.
+///
+/// DER keys are read as PKCS#8, falling back to the algorithm specific encodings.
+pub fn pkey_from_file(file: &mut dyn Read, passphrase: Option<&[u8]>, format: Format) -> Result<PKey<Private>, LoadError> {
     let mut pkey_bytes = Vec::<u8>::with_capacity(2048);
     file.read_to_end(&mut pkey_bytes)?;
-    let res = PKey::<Private>::private_key_from_pem(pkey_bytes.as_ref())?;
-    Ok(res)
+
+    let asked = Cell::new(false);
+
+    let callback = |buf: &mut [u8]| {
+        asked.set(true);
+
+        match passphrase {
+            Some(passphrase) if passphrase.len() <= buf.len() => {
+                buf[..passphrase.len()].copy_from_slice(passphrase);
+                Ok(passphrase.len())
+            }
+            _ => Err(ErrorStack::get()),
+        }
+    };
+
+    let res = if format.detect(&pkey_bytes).is_der() {
+        PKey::<Private>::private_key_from_pkcs8_callback(pkey_bytes.as_ref(), callback)
+            .or_else(|err| if asked.get() { Err(err) } else { PKey::private_key_from_der(pkey_bytes.as_ref()) })
+    } else {
+        PKey::<Private>::private_key_from_pem_callback(pkey_bytes.as_ref(), callback)
+    };
+
+    match res {
+        Ok(res) => Ok(res),
+        Err(_) if asked.get() && passphrase.is_none() => Err(LoadError::PassphraseRequired),
+        Err(_) if asked.get() => Err(LoadError::WrongPassphrase),
+        Err(err) => Err(LoadError::WrongFormat("private key", err)),
+    }
 }
 
-pub fn pkey_public_from_file(file: &mut Read) -> Result<PKey<Public>, LoadError> {
+pub fn pkey_public_from_file(file: &mut dyn Read, format: Format) -> Result<PKey<Public>, LoadError> {
     let mut pkey_bytes = Vec::<u8>::with_capacity(2048);
     file.read_to_end(&mut pkey_bytes)?;
 
-    let res = PKey::<Public>::public_key_from_pem(pkey_bytes.as_ref())?;
+    let res = if format.detect(&pkey_bytes).is_der() {
+        PKey::<Public>::public_key_from_der(pkey_bytes.as_ref()).map_err(wrong_format("public key"))?
+    } else {
+        PKey::<Public>::public_key_from_pem(pkey_bytes.as_ref()).map_err(wrong_format("public key"))?
+    };
+
     Ok(res)
 }
 
-pub fn pkey_to_file(file: &mut Write, pkey: &PKey<Private>) -> Result<(), LoadError> {
-    file.write(pkey.private_key_to_pem_pkcs8()?.as_ref())?;
+/// Cipher and passphrase used to encrypt a private key written by `pkey_to_file`
+pub struct KeyEncryption<'a> {
+    pub cipher: Cipher,
+    pub passphrase: &'a [u8],
+}
+
+/// Write a private key as PKCS#8, encrypted when `encryption` is given
+pub fn pkey_to_file(
+    file: &mut dyn Write,
+    pkey: &PKey<Private>,
+    encryption: Option<&KeyEncryption>,
+    format: Format,
+) -> Result<(), LoadError> {
+    let bytes = match (encryption, format.is_der()) {
+        (Some(x), false) => pkey.private_key_to_pem_pkcs8_passphrase(x.cipher, x.passphrase)?,
+        (Some(x), true) => pkey.private_key_to_pkcs8_passphrase(x.cipher, x.passphrase)?,
+        (None, false) => pkey.private_key_to_pem_pkcs8()?,
+        (None, true) => pkey.private_key_to_pkcs8()?,
+    };
+
+    file.write_all(bytes.as_ref())?;
 
     Ok(())
 }
 
-pub fn pkey_public_to_file(file: &mut Write, pkey: &PKey<Private>) -> Result<(), LoadError> {
-    file.write(pkey.public_key_to_pem()?.as_ref())?;
+pub fn pkey_public_to_file(file: &mut dyn Write, pkey: &PKey<Private>, format: Format) -> Result<(), LoadError> {
+    let bytes = if format.is_der() { pkey.public_key_to_der()? } else { pkey.public_key_to_pem()? };
+    file.write_all(bytes.as_ref())?;
 
     Ok(())
 }
 
-pub fn cert_from_file(file: &mut Read) -> Result<X509, LoadError> {
+pub fn cert_from_file(file: &mut dyn Read, format: Format) -> Result<X509, LoadError> {
     let mut pkey_bytes = Vec::<u8>::with_capacity(2048);
     file.read_to_end(&mut pkey_bytes)?;
-    let res = X509::from_pem(pkey_bytes.as_ref())?;
+
+    let res = if format.detect(&pkey_bytes).is_der() {
+        X509::from_der(pkey_bytes.as_ref()).map_err(wrong_format("certificate"))?
+    } else {
+        X509::from_pem(pkey_bytes.as_ref()).map_err(wrong_format("certificate"))?
+    };
+
     Ok(res)
 }
 
+/// Load every certificate of a PEM bundle, or the single certificate of a DER file
+pub fn certs_from_file(file: &mut dyn Read, format: Format) -> Result<Vec<X509>, LoadError> {
+    let mut bytes = Vec::<u8>::with_capacity(4096);
+    file.read_to_end(&mut bytes)?;
 
-pub fn cert_to_file(file: &mut Write, cert: &X509) -> Result<(), LoadError> {
-    file.write(cert.to_pem()?.as_ref())?;
+    if format.detect(&bytes).is_der() {
+        Ok(vec![X509::from_der(&bytes).map_err(wrong_format("certificate"))?])
+    } else {
+        X509::stack_from_pem(&bytes).map_err(wrong_format("certificate"))
+    }
+}
+
+pub fn cert_to_file(file: &mut dyn Write, cert: &X509, format: Format) -> Result<(), LoadError> {
+    let bytes = if format.is_der() { cert.to_der()? } else { cert.to_pem()? };
+    file.write_all(bytes.as_ref())?;
 
     Ok(())
 }
 
-pub fn csr_from_file(file: &mut Read) -> Result<X509Req, LoadError> {
+/// Write certificates one after another, DER can only hold one
+pub fn certs_to_file(file: &mut dyn Write, certs: &[X509], format: Format) -> Result<(), LoadError> {
+    if format.is_der() && certs.len() > 1 {
+        return Err(IOError::new(ErrorKind::InvalidInput, "a DER file holds a single certificate").into());
+    }
+
+    for cert in certs {
+        cert_to_file(file, cert, format)?;
+    }
+
+    Ok(())
+}
+
+/// Write a certificate followed by its chain, as servers such as nginx expect
+pub fn fullchain_to_file(file: &mut dyn Write, cert: &X509, chain: &[X509]) -> Result<(), LoadError> {
+    cert_to_file(file, cert, Format::Pem)?;
+    certs_to_file(file, chain, Format::Pem)
+}
+
+/// Write an unencrypted private key followed by its certificate and chain in one PEM file
+pub fn bundle_to_file(file: &mut dyn Write, pkey: &PKeyRef<Private>, cert: &X509, chain: &[X509]) -> Result<(), LoadError> {
+    if !cert.public_key()?.public_eq(pkey) {
+        return Err(LoadError::KeyMismatch);
+    }
+
+    file.write_all(&pkey.private_key_to_pem_pkcs8()?)?;
+    fullchain_to_file(file, cert, chain)
+}
+
+/// Bundle a private key, its certificate and the CA chain into a password protected PKCS#12
+pub fn build_pkcs12(
+    pkey: &PKeyRef<Private>,
+    cert: &X509Ref,
+    chain: &[X509],
+    name: &str,
+    password: &str,
+) -> Result<Pkcs12, LoadError> {
+    if !cert.public_key()?.public_eq(pkey) {
+        return Err(LoadError::KeyMismatch);
+    }
+
+    let mut builder = Pkcs12::builder();
+    builder.name(name).pkey(pkey).cert(cert);
+
+    if !chain.is_empty() {
+        let mut ca = Stack::<X509>::new()?;
+
+        for x in chain {
+            ca.push(x.clone())?;
+        }
+
+        builder.ca(ca);
+    }
+
+    Ok(builder.build2(password)?)
+}
+
+/// Contents of a PKCS#12 bundle
+pub struct Pkcs12Contents {
+    pub pkey: Option<PKey<Private>>,
+    pub cert: Option<X509>,
+    pub chain: Vec<X509>,
+}
+
+/// Load a DER PKCS#12 bundle, failing with `WrongPassphrase` when it cannot be decrypted
+pub fn pkcs12_from_file(file: &mut dyn Read, password: &str) -> Result<Pkcs12Contents, LoadError> {
+    let mut bytes = Vec::<u8>::with_capacity(4096);
+    file.read_to_end(&mut bytes)?;
+
+    let parsed = Pkcs12::from_der(&bytes).map_err(wrong_format("PKCS#12 bundle"))?
+        .parse2(password)
+        .map_err(|_| LoadError::WrongPassphrase)?;
+
+    Ok(Pkcs12Contents {
+        pkey: parsed.pkey,
+        cert: parsed.cert,
+        chain: parsed.ca.map(|x| x.into_iter().collect()).unwrap_or_default(),
+    })
+}
+
+pub fn pkcs12_to_file(file: &mut dyn Write, pkcs12: &Pkcs12) -> Result<(), LoadError> {
+    file.write_all(pkcs12.to_der()?.as_ref())?;
+
+    Ok(())
+}
+
+pub fn csr_from_file(file: &mut dyn Read, format: Format) -> Result<X509Req, LoadError> {
     let mut pkey_bytes = Vec::<u8>::with_capacity(2048);
     file.read_to_end(&mut pkey_bytes)?;
-    let res = X509Req::from_pem(pkey_bytes.as_ref())?;
+
+    let res = if format.detect(&pkey_bytes).is_der() {
+        X509Req::from_der(pkey_bytes.as_ref()).map_err(wrong_format("certificate request"))?
+    } else {
+        X509Req::from_pem(pkey_bytes.as_ref()).map_err(wrong_format("certificate request"))?
+    };
+
     Ok(res)
 }
 
-pub fn csr_to_file(file: &mut Write, csr: &X509Req) -> Result<(), LoadError> {
-    file.write(csr.to_pem()?.as_ref())?;
+pub fn csr_to_file(file: &mut dyn Write, csr: &X509Req, format: Format) -> Result<(), LoadError> {
+    let bytes = if format.is_der() { csr.to_der()? } else { csr.to_pem()? };
+    file.write_all(bytes.as_ref())?;
 
     Ok(())
 }
 
+pub fn crl_from_file(file: &mut dyn Read, format: Format) -> Result<X509Crl, LoadError> {
+    let mut bytes = Vec::<u8>::with_capacity(2048);
+    file.read_to_end(&mut bytes)?;
+
+    let res = if format.detect(&bytes).is_der() {
+        X509Crl::from_der(bytes.as_ref()).map_err(wrong_format("crl"))?
+    } else {
+        X509Crl::from_pem(bytes.as_ref()).map_err(wrong_format("crl"))?
+    };
+
+    Ok(res)
+}
+
+pub fn crl_to_file(file: &mut dyn Write, crl: &X509Crl, format: Format) -> Result<(), LoadError> {
+    let bytes = if format.is_der() { crl.to_der()? } else { crl.to_pem()? };
+    file.write_all(bytes.as_ref())?;
+
+    Ok(())
+}
@@ -0,0 +1,176 @@
+//! Output files written to a temporary file first and moved into place once complete
+
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+
+/// What happens when an output file exists already
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overwrite {
+    /// Fail with `ErrorKind::AlreadyExists`
+    #[default]
+    Refuse,
+    /// Replace the file
+    Replace,
+    /// Rename the file to `<name>.bak`, replacing an older backup, then replace it
+    Backup,
+}
+
+/// Who may read and write an output file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Access {
+    /// Permissions follow the umask, for certificates, requests and CRLs
+    #[default]
+    Shared,
+    /// Only the owner has access (mode 0600), for anything holding a private key
+    Owner,
+}
+
+impl Access {
+    /// Mode given to new files, `None` to leave it to the umask
+    pub fn mode(self) -> Option<u32> {
+        match self {
+            Access::Shared => None,
+            Access::Owner => Some(0o600),
+        }
+    }
+}
+
+/// Mode of the directories created for private keys, such as a CA state directory
+pub const PRIVATE_DIR_MODE: u32 = 0o700;
+
+/// Create `path` and its missing parents as directories only the owner has access to.
+///
+/// Directories that exist already keep their permissions.
+pub fn create_private_dir<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+
+    #[cfg(unix)]
+    builder.mode(PRIVATE_DIR_MODE);
+
+    builder.create(path)
+}
+
+/// Permission bits of `path` that give users other than the owner access to it, 0 if there are none
+pub fn open_permissions<P: AsRef<Path>>(path: P) -> io::Result<u32> {
+    let metadata = fs::metadata(path)?;
+
+    #[cfg(unix)]
+    return Ok(metadata.permissions().mode() & 0o077);
+
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        Ok(0)
+    }
+}
+
+/// A file written next to its destination and renamed over it by `commit`.
+///
+/// Dropping it without committing removes the temporary file, so an interrupted write never
+/// leaves a truncated file at the destination.
+pub struct OutputFile {
+    path: PathBuf,
+    tmp: PathBuf,
+    file: File,
+    overwrite: Overwrite,
+}
+
+/// `<name>.bak` next to `path`
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".bak");
+
+    path.with_file_name(name)
+}
+
+fn exists_error(path: &Path) -> io::Error {
+    io::Error::new(ErrorKind::AlreadyExists, format!("{} already exists", path.display()))
+}
+
+impl OutputFile {
+    /// Start writing `path`, failing early when it exists and `overwrite` refuses to replace it.
+    ///
+    /// The temporary file is created with the permissions of `access`, which the file keeps once
+    /// renamed, so a replaced key is never readable by others in between.
+    pub fn create<P: AsRef<Path>>(path: P, overwrite: Overwrite, access: Access) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        if overwrite == Overwrite::Refuse && fs::symlink_metadata(&path).is_ok() {
+            return Err(exists_error(&path));
+        }
+
+        let mut name = std::ffi::OsString::from(".");
+        name.push(path.file_name().ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "not a file path"))?);
+        name.push(format!(".{}.tmp", process::id()));
+
+        let tmp = path.with_file_name(name);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        if let Some(mode) = access.mode() {
+            options.mode(mode);
+        }
+
+        #[cfg(not(unix))]
+        let _ = access;
+
+        let file = options.open(&tmp)?;
+
+        Ok(OutputFile { path, tmp, file, overwrite })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Flush the contents to disk and move them to the destination
+    pub fn commit(self) -> io::Result<()> {
+        self.file.sync_all()?;
+
+        match self.overwrite {
+            Overwrite::Refuse => {
+                // linking fails when the destination appeared in the meantime, unlike renaming
+                fs::hard_link(&self.tmp, &self.path).map_err(|err| match err.kind() {
+                    ErrorKind::AlreadyExists => exists_error(&self.path),
+                    _ => err,
+                })?;
+                fs::remove_file(&self.tmp)?;
+            }
+            Overwrite::Replace => fs::rename(&self.tmp, &self.path)?,
+            Overwrite::Backup => {
+                match fs::rename(&self.path, backup_path(&self.path)) {
+                    Err(ref err) if err.kind() == ErrorKind::NotFound => {}
+                    res => res?,
+                }
+
+                fs::rename(&self.tmp, &self.path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        // after a commit the temporary file is gone already
+        let _ = fs::remove_file(&self.tmp);
+    }
+}
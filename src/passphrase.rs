@@ -0,0 +1,85 @@
+use std::io::{BufRead, BufReader, Error as IOError, ErrorKind, Write};
+
+#[cfg(unix)]
+use std::fs::{File, OpenOptions};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+
+/// Read a passphrase from the terminal without echoing it back
+pub fn prompt_passphrase(prompt: &str) -> Result<Vec<u8>, IOError> {
+    let mut line = String::new();
+
+    #[cfg(unix)]
+    {
+        let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+        let mut out = tty.try_clone()?;
+
+        out.write_all(prompt.as_bytes())?;
+        out.flush()?;
+
+        let echo = EchoGuard::disable(&tty)?;
+        BufReader::new(&tty).read_line(&mut line)?;
+        drop(echo);
+    }
+
+    #[cfg(not(unix))]
+    {
+        let mut out = std::io::stderr();
+        out.write_all(prompt.as_bytes())?;
+        out.flush()?;
+
+        BufReader::new(std::io::stdin()).read_line(&mut line)?;
+    }
+
+    if line.is_empty() {
+        return Err(IOError::new(ErrorKind::UnexpectedEof, "no passphrase entered"));
+    }
+
+    Ok(strip_newline(line.as_bytes()).to_vec())
+}
+
+/// Strip a single trailing `\n` or `\r\n`
+pub fn strip_newline(x: &[u8]) -> &[u8] {
+    let x = x.strip_suffix(b"\n").unwrap_or(x);
+    x.strip_suffix(b"\r").unwrap_or(x)
+}
+
+/// Turns terminal echo off and restores the previous settings on drop
+#[cfg(unix)]
+struct EchoGuard {
+    fd: i32,
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl EchoGuard {
+    fn disable(tty: &File) -> Result<Self, IOError> {
+        let fd = tty.as_raw_fd();
+
+        unsafe {
+            let mut original = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(fd, &mut original) != 0 {
+                return Err(IOError::last_os_error());
+            }
+
+            let mut silent = original;
+            silent.c_lflag &= !libc::ECHO;
+            silent.c_lflag |= libc::ECHONL;
+
+            if libc::tcsetattr(fd, libc::TCSANOW, &silent) != 0 {
+                return Err(IOError::last_os_error());
+            }
+
+            Ok(EchoGuard { fd, original })
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for EchoGuard {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(self.fd, libc::TCSANOW, &self.original);
+        }
+    }
+}
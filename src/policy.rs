@@ -0,0 +1,456 @@
+//! Rules a CA applies to the requests it signs
+
+use openssl::asn1::{Asn1BitString, Asn1Object, Asn1OctetString, Asn1Time};
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::stack::Stack;
+use openssl::x509::{GeneralName, X509Extension, X509ExtensionRef, X509NameRef, X509ReqRef};
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Read};
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::{LoadError, SignError, Validity, extension_nid, extension_value, general_name_upn};
+use crate::constraints::{in_network, is_host_name, uri_host, Subtree};
+use crate::der::{tlv, TAG_OID, TAG_SEQUENCE, TAG_URI};
+
+/// id-ad-ocsp, 1.3.6.1.5.5.7.48.1
+const OID_OCSP: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01];
+/// id-ad-caIssuers, 1.3.6.1.5.5.7.48.2
+const OID_CA_ISSUERS: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x02];
+
+/// What happens to an extension carried by a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtensionAction {
+    /// Copy the extension into the certificate
+    Copy,
+    /// Drop the extension, the certificate gets the CA's own value if it sets one
+    Override,
+    /// Refuse to sign the request
+    Reject,
+}
+
+impl ExtensionAction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "copy" => Some(ExtensionAction::Copy),
+            "override" => Some(ExtensionAction::Override),
+            "reject" => Some(ExtensionAction::Reject),
+            _ => None,
+        }
+    }
+}
+
+/// Where relying parties fetch the certificate of a CA, the status of the certificates it issued
+/// and its CRLs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IssuerUrls {
+    /// caIssuers URLs of the authorityInfoAccess extension
+    pub ca_issuers: Vec<String>,
+    /// OCSP responder URLs of the authorityInfoAccess extension
+    pub ocsp: Vec<String>,
+    /// URLs of the cRLDistributionPoints extension
+    pub crl: Vec<String>,
+}
+
+impl IssuerUrls {
+    pub fn is_empty(&self) -> bool {
+        self.ca_issuers.is_empty() && self.ocsp.is_empty() && self.crl.is_empty()
+    }
+
+    /// The authorityInfoAccess extension, `None` without caIssuers and OCSP URLs
+    pub fn authority_info_access(&self) -> Result<Option<X509Extension>, ErrorStack> {
+        if self.ca_issuers.is_empty() && self.ocsp.is_empty() {
+            return Ok(None);
+        }
+
+        let descriptions = self.ocsp.iter().map(|x| (OID_OCSP, x))
+            .chain(self.ca_issuers.iter().map(|x| (OID_CA_ISSUERS, x)))
+            .flat_map(|(method, url)| tlv(TAG_SEQUENCE, &[tlv(TAG_OID, method), tlv(TAG_URI, url.as_bytes())].concat()))
+            .collect::<Vec<_>>();
+
+        let oid = Asn1Object::from_str("1.3.6.1.5.5.7.1.1")?;
+        let value = Asn1OctetString::new_from_bytes(&tlv(TAG_SEQUENCE, &descriptions))?;
+
+        Ok(Some(X509Extension::new_from_der(&oid, false, &value)?))
+    }
+
+    /// The cRLDistributionPoints extension, a single distribution point with every URL as its
+    /// full name, `None` without CRL URLs
+    pub fn crl_distribution_points(&self) -> Result<Option<X509Extension>, ErrorStack> {
+        if self.crl.is_empty() {
+            return Ok(None);
+        }
+
+        let names = self.crl.iter().flat_map(|x| tlv(TAG_URI, x.as_bytes())).collect::<Vec<_>>();
+        // DistributionPoint { distributionPoint [0] { fullName [0] GeneralNames } }
+        let point = tlv(TAG_SEQUENCE, &tlv(0xa0, &tlv(0xa0, &names)));
+
+        let oid = Asn1Object::from_str("2.5.29.31")?;
+        let value = Asn1OctetString::new_from_bytes(&tlv(TAG_SEQUENCE, &point))?;
+
+        Ok(Some(X509Extension::new_from_der(&oid, false, &value)?))
+    }
+}
+
+/// Signing policy of a CA, read from `ca.conf` in its state directory.
+///
+/// The file holds `key = value` lines, `#` at the start of a line or after whitespace starts a
+/// comment:
+///
+/// ```text
+/// extension.subjectAltName = copy
+/// other_extensions = reject
+/// subject_fields = CN, O, C
+/// san_domains = example.com, example.org
+/// san_ips = 10.0.0.0/8, fd00::/8
+/// max_days = 825
+/// ca_issuers = http://pki.example.com/ca.crt
+/// ocsp = http://ocsp.example.com
+/// crl_distribution_points = http://pki.example.com/ca.crl
+/// ```
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// Action for each extension type a request may carry
+    pub extensions: Vec<(Nid, ExtensionAction)>,
+    /// Action for extension types not listed in `extensions`
+    pub other_extensions: ExtensionAction,
+    /// Subject fields a request may use, any when `None`
+    pub subject_fields: Option<Vec<Nid>>,
+    /// Domains the DNS names, email addresses, URI hosts and user principal names of the
+    /// subjectAltName and the host name and emailAddress of the subject must fall under, any
+    /// when `None`
+    pub san_domains: Option<Vec<String>>,
+    /// Networks IP addresses must fall under, as address and prefix length. When `None` any
+    /// address is allowed, unless `san_domains` is set
+    pub san_ips: Option<Vec<(IpAddr, u8)>>,
+    /// Longest validity of a certificate in days, unlimited when `None`
+    pub max_days: Option<u32>,
+    /// URLs stamped into every certificate the CA signs
+    pub urls: IssuerUrls,
+}
+
+impl Default for Policy {
+    /// Key purposes and alternative names are copied, the CA sets the basic constraints and
+    /// key identifiers itself and any other extension is rejected. Copied key usages may not
+    /// include keyCertSign or cRLSign.
+    fn default() -> Self {
+        Policy {
+            extensions: vec![
+                (Nid::BASIC_CONSTRAINTS, ExtensionAction::Override),
+                (Nid::SUBJECT_KEY_IDENTIFIER, ExtensionAction::Override),
+                (Nid::AUTHORITY_KEY_IDENTIFIER, ExtensionAction::Override),
+                (Nid::KEY_USAGE, ExtensionAction::Copy),
+                (Nid::EXT_KEY_USAGE, ExtensionAction::Copy),
+                (Nid::SUBJECT_ALT_NAME, ExtensionAction::Copy),
+            ],
+            other_extensions: ExtensionAction::Reject,
+            subject_fields: None,
+            san_domains: None,
+            san_ips: None,
+            max_days: None,
+            urls: IssuerUrls::default(),
+        }
+    }
+}
+
+fn nid_from_name(name: &str) -> Option<Nid> {
+    let nid = Asn1Object::from_str(name).ok()?.nid();
+
+    if nid == Nid::UNDEF { None } else { Some(nid) }
+}
+
+fn nid_name(nid: Nid) -> String {
+    nid.short_name().map(|x| x.to_string()).unwrap_or_else(|_| format!("{:?}", nid))
+}
+
+fn split_list(value: &str) -> impl Iterator<Item=&str> {
+    value.split(',').map(|x| x.trim()).filter(|x| !x.is_empty())
+}
+
+/// Drop a comment from a line, started by a `#` at its start or after whitespace so URLs may
+/// carry fragments
+fn strip_comment(line: &str) -> &str {
+    let start = line.char_indices()
+        .find(|(i, c)| *c == '#' && line[..*i].chars().next_back().is_none_or(char::is_whitespace))
+        .map(|(i, _)| i);
+
+    &line[..start.unwrap_or(line.len())]
+}
+
+/// Whether `name` is `domain` itself or one of its subdomains
+fn in_domain(name: &str, domain: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    let domain = domain.trim_start_matches('.').trim_end_matches('.');
+
+    name.eq_ignore_ascii_case(domain) || (
+        name.len() > domain.len() &&
+            name.as_bytes()[name.len() - domain.len() - 1] == b'.' &&
+            name[name.len() - domain.len()..].eq_ignore_ascii_case(domain)
+    )
+}
+
+impl Policy {
+    pub fn parse(contents: &str) -> Result<Self, LoadError> {
+        let mut policy = Policy::default();
+
+        for (idx, line) in contents.lines().enumerate() {
+            let invalid = |msg: &str| LoadError::Config(format!("line {}: {}", idx + 1, msg));
+
+            let line = strip_comment(line).trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or_default().trim();
+            let value = parts.next().ok_or_else(|| invalid("expected key = value"))?.trim();
+
+            let action = || ExtensionAction::from_name(value)
+                .ok_or_else(|| invalid("expected copy, override or reject"));
+
+            let urls = || split_list(value)
+                .map(|x| if x.contains("://") { Ok(x.to_string()) } else { Err(invalid("expected URLs")) })
+                .collect::<Result<Vec<_>, _>>();
+
+            if let Some(name) = key.strip_prefix("extension.") {
+                let nid = nid_from_name(name).ok_or_else(|| invalid("unknown extension"))?;
+                let action = action()?;
+
+                policy.extensions.retain(|(x, _)| *x != nid);
+                policy.extensions.push((nid, action));
+                continue;
+            }
+
+            match key {
+                "other_extensions" => policy.other_extensions = action()?,
+                "subject_fields" => {
+                    let fields = split_list(value)
+                        .map(|x| nid_from_name(x).ok_or_else(|| invalid("unknown subject field")))
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    policy.subject_fields = Some(fields);
+                }
+                "san_domains" => policy.san_domains = Some(split_list(value).map(|x| x.to_string()).collect()),
+                "san_ips" => {
+                    let networks = split_list(value)
+                        .map(|x| match Subtree::parse(&format!("IP:{}", x)) {
+                            Ok(Subtree::Ip(addr, prefix)) => Ok((addr, prefix)),
+                            _ => Err(invalid("expected IP networks")),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    policy.san_ips = Some(networks);
+                }
+                "max_days" => policy.max_days = Some(value.parse().map_err(|_| invalid("expected a number of days"))?),
+                "ca_issuers" => policy.urls.ca_issuers = urls()?,
+                "ocsp" => policy.urls.ocsp = urls()?,
+                "crl_distribution_points" => policy.urls.crl = urls()?,
+                _ => return Err(invalid("unknown key")),
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// Read `ca.conf` from a CA state directory, falling back to the default policy
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Self, LoadError> {
+        match OpenOptions::new().read(true).open(dir.as_ref().join("ca.conf")) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)?;
+                Policy::parse(&contents)
+            }
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(Policy::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn extension_action(&self, nid: Nid) -> ExtensionAction {
+        self.extensions.iter()
+            .find(|(x, _)| *x == nid)
+            .map(|(_, action)| *action)
+            .unwrap_or(self.other_extensions)
+    }
+
+    fn check_domain(&self, name: &str, domain: &str, violations: &mut Vec<String>) {
+        if let Some(domains) = &self.san_domains {
+            if !domains.iter().any(|x| in_domain(domain, x)) {
+                violations.push(format!("{} is outside the allowed domains", name));
+            }
+        }
+    }
+
+    fn check_ip(&self, name: &str, ip: IpAddr, violations: &mut Vec<String>) {
+        let allowed = match &self.san_ips {
+            Some(networks) => networks.iter().any(|(addr, prefix)| in_network(ip, *addr, *prefix)),
+            None => self.san_domains.is_none(),
+        };
+
+        if !allowed {
+            violations.push(format!("{} is outside the allowed networks", name));
+        }
+    }
+
+    fn check_names(&self, ext: &X509ExtensionRef, violations: &mut Vec<String>) {
+        if self.san_domains.is_none() && self.san_ips.is_none() {
+            return;
+        }
+
+        let names = match unsafe { extension_value::<Stack<GeneralName>>(ext) } {
+            Some(x) => x,
+            None => {
+                violations.push("subjectAltName could not be decoded".to_string());
+                return;
+            }
+        };
+
+        for name in names.iter() {
+            if let Some(x) = name.dnsname() {
+                self.check_domain(&format!("DNS:{}", x), x, violations);
+            } else if let Some(x) = name.email() {
+                self.check_domain(&format!("email:{}", x), x.rsplit('@').next().unwrap_or_default(), violations);
+            } else if let Some(x) = name.uri() {
+                let name = format!("URI:{}", x);
+
+                match uri_host(x) {
+                    Some(host) => match host.parse::<IpAddr>() {
+                        Ok(ip) => self.check_ip(&name, ip, violations),
+                        Err(_) => self.check_domain(&name, host, violations),
+                    },
+                    None if self.san_domains.is_some() => violations.push(format!("{} has no host to check against the allowed domains", name)),
+                    None => {}
+                }
+            } else if let Some(x) = name.ipaddress() {
+                let ip = match x.len() {
+                    4 => IpAddr::from(<[u8; 4]>::try_from(x).unwrap()),
+                    16 => IpAddr::from(<[u8; 16]>::try_from(x).unwrap()),
+                    _ => {
+                        violations.push(format!("IP:{:02X?} is not an IP address", x));
+                        continue;
+                    }
+                };
+
+                self.check_ip(&format!("IP:{}", ip), ip, violations);
+            } else if let Some(x) = general_name_upn(name) {
+                self.check_domain(&format!("UPN:{}", x), x.rsplit('@').next().unwrap_or_default(), violations);
+            }
+        }
+    }
+
+    /// Check the host name in the common name and the emailAddress of a subject, which clients
+    /// may still take for the names of the certificate
+    fn check_subject_names(&self, subject: &X509NameRef, violations: &mut Vec<String>) {
+        for entry in subject.entries() {
+            let value = match std::str::from_utf8(entry.data().as_slice()) {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+
+            match entry.object().nid() {
+                Nid::COMMONNAME if is_host_name(value) => {
+                    self.check_domain(&format!("subject CN {}", value), value, violations);
+                }
+                Nid::PKCS9_EMAILADDRESS => {
+                    self.check_domain(&format!("subject emailAddress {}", value), value.rsplit('@').next().unwrap_or_default(), violations);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Refuse the CA key usages in a copied keyUsage, unless the policy copies the requested
+    /// basic constraints as well
+    fn check_key_usage(&self, ext: &X509ExtensionRef, violations: &mut Vec<String>) {
+        if self.extension_action(Nid::BASIC_CONSTRAINTS) == ExtensionAction::Copy {
+            return;
+        }
+
+        let bits = match unsafe { extension_value::<Asn1BitString>(ext) } {
+            Some(x) => x.as_slice().first().copied().unwrap_or_default(),
+            None => {
+                violations.push("keyUsage could not be decoded".to_string());
+                return;
+            }
+        };
+
+        // keyCertSign is bit 5 and cRLSign bit 6, counted from the most significant bit
+        for (mask, name) in &[(0x04, "keyCertSign"), (0x02, "cRLSign")] {
+            if bits & mask != 0 {
+                violations.push(format!("key usage {} is only allowed in CA certificates", name));
+            }
+        }
+    }
+
+    /// Check a request and its validity against the policy.
+    ///
+    /// Returns the request extensions to copy into the certificate, or every violation found.
+    pub fn apply(&self, req: &X509ReqRef, not_before_after: &Validity) -> Result<Vec<X509Extension>, SignError> {
+        let mut violations = Vec::<String>::default();
+
+        if let Some(fields) = &self.subject_fields {
+            for entry in req.subject_name().entries() {
+                let nid = entry.object().nid();
+
+                if !fields.contains(&nid) {
+                    violations.push(format!("subject field {} is not allowed", nid_name(nid)));
+                }
+            }
+        }
+
+        self.check_subject_names(req.subject_name(), &mut violations);
+
+        let mut copied = Vec::<X509Extension>::default();
+
+        for ext in req.extensions().or_else(|_| Stack::<X509Extension>::new())? {
+            let nid = extension_nid(&ext);
+
+            if nid == Nid::SUBJECT_ALT_NAME {
+                self.check_names(&ext, &mut violations);
+            }
+
+            match self.extension_action(nid) {
+                ExtensionAction::Copy => {
+                    if nid == Nid::KEY_USAGE {
+                        self.check_key_usage(&ext, &mut violations);
+                    }
+
+                    copied.push(ext)
+                }
+                ExtensionAction::Override => {}
+                ExtensionAction::Reject => violations.push(format!("extension {} is not allowed", nid_name(nid))),
+            }
+        }
+
+        if let Some(max_days) = self.max_days {
+            match not_before_after {
+                (_, None) => violations.push("the certificate has no expiry date".to_string()),
+                (not_before, Some(not_after)) => {
+                    // a backdated notBefore only tolerates clock skew, the lifetime counts from now
+                    let now = Asn1Time::days_from_now(0)?;
+                    let start = match not_before {
+                        Some(x) if now.compare(x)? == Ordering::Less => x,
+                        _ => &now,
+                    };
+                    let diff = start.diff(not_after)?;
+
+                    if diff.days > max_days as i32 || (diff.days == max_days as i32 && diff.secs > 0) {
+                        violations.push(format!(
+                            "validity of {} days exceeds the maximum of {}",
+                            diff.days + if diff.secs > 0 { 1 } else { 0 }, max_days,
+                        ));
+                    }
+                }
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(copied)
+        } else {
+            Err(SignError::Policy(violations))
+        }
+    }
+}
@@ -2,6 +2,7 @@
 
 use openssl::ssl::SslAcceptor;
 use openssl::ssl::SslMethod;
+use openssl::stack::Stack;
 
 use crate::*;
 use std::net::{TcpListener, TcpStream};
@@ -11,23 +12,35 @@ use tempfile::tempdir;
 use std::path::Path;
 use std::fs::OpenOptions;
 use std::io::Write;
+use openssl::symm::Cipher;
+use openssl::x509::store::X509StoreBuilder;
+use crate::db::{CaDatabase, Status};
+use crate::policy::{ExtensionAction, IssuerUrls, Policy};
+use crate::inspect::{describe, read_object, Object, Value};
+use crate::verify::{verify_chain, Purpose, VerifyOptions};
+use crate::time::{asn1_to_unix, parse_time};
+use crate::constraints::{NameConstraints, Subtree};
+use crate::output::{backup_path, create_private_dir, open_permissions, Access, OutputFile, Overwrite};
 use clap::App;
 use crate::args::parser_name_builder;
 use crate::args::parser_not_after_before;
 use crate::args::matches_name_builder;
+use crate::args::parse_subject;
+use crate::args::validate_name;
 use crate::args::matches_not_after_before;
 use crate::args::ParseError;
 use crate::args::parser_csr_extensions;
 use crate::args::matches_csr_extensions;
 use crate::args::run_csr_extensions;
 use crate::args::CsrExt;
+use crate::args::SanEntry;
 
 fn priv_to_pub(server_key: &PKey<Private>) -> PKey<Public> {
-    return PKey::<Public>::public_key_from_pem(server_key.public_key_to_pem().unwrap().as_ref()).unwrap();
+    PKey::<Public>::public_key_from_pem(server_key.public_key_to_pem().unwrap().as_ref()).unwrap()
 }
 
 
-fn create_name_validity(name: &str) -> Result<(X509Name, (Option<Asn1Time>, Option<Asn1Time>)), ParseError> {
+fn create_name_validity(name: &str) -> Result<(X509Name, Validity), ParseError> {
     let app = App::new("asd");
     let app = parser_name_builder(app);
     let app = parser_not_after_before(app);
@@ -38,7 +51,7 @@ fn create_name_validity(name: &str) -> Result<(X509Name, (Option<Asn1Time>, Opti
     Ok((matches_name_builder(&matches)?, matches_not_after_before(&matches)?))
 }
 
-fn create_server(name: &str) -> Result<(Vec<CsrExt>, X509Name, (Option<Asn1Time>, Option<Asn1Time>)), ParseError> {
+fn create_server(name: &str) -> Result<(Vec<CsrExt>, X509Name, Validity), ParseError> {
     let app = App::new("asd");
     let app = parser_name_builder(app);
     let app = parser_csr_extensions(app);
@@ -50,7 +63,7 @@ fn create_server(name: &str) -> Result<(Vec<CsrExt>, X509Name, (Option<Asn1Time>
     Ok((matches_csr_extensions(&matches)?, matches_name_builder(&matches)?, matches_not_after_before(&matches)?))
 }
 
-fn create_client(name: &str) -> Result<(Vec<CsrExt>, X509Name, (Option<Asn1Time>, Option<Asn1Time>)), ParseError> {
+fn create_client(name: &str) -> Result<(Vec<CsrExt>, X509Name, Validity), ParseError> {
     let app = App::new("asd");
     let app = parser_name_builder(app);
     let app = parser_csr_extensions(app);
@@ -62,6 +75,29 @@ fn create_client(name: &str) -> Result<(Vec<CsrExt>, X509Name, (Option<Asn1Time>
     Ok((matches_csr_extensions(&matches)?, matches_name_builder(&matches)?, matches_not_after_before(&matches)?))
 }
 
+/// A self-signed EC P-256 CA with its private key and the validity it was built with
+fn create_ca(name: &str) -> (PKey<Private>, X509, Validity) {
+    let (name, val) = create_name_validity(name).unwrap();
+    let key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let ca = build_ca_cert(&key, &name, None, &val).unwrap();
+
+    (key, ca, val)
+}
+
+/// Add the extensions selected on the command line to a request, as `simpleca csr` does
+fn add_csr_extensions(exts: &[CsrExt], key: Id) -> impl FnOnce(&mut X509ReqBuilder) -> Result<(), ErrorStack> + '_ {
+    move |req_builder| {
+        let mut extensions = Stack::<X509Extension>::new()?;
+        run_csr_extensions(exts, key, &mut extensions, req_builder)?;
+        req_builder.add_extensions(&extensions)?;
+        Ok(())
+    }
+}
+
+fn csr_with_exts(key: &PKey<Private>, name: &X509Name, exts: &[CsrExt]) -> X509Req {
+    build_ca_req(key, name, add_csr_extensions(exts, key.id())).unwrap()
+}
+
 
 #[test]
 fn test_initialization() {
@@ -69,22 +105,23 @@ fn test_initialization() {
 
     let (name, val) = create_name_validity("ca").unwrap();
 
-    let key = build_privkey().unwrap();
+    let key = build_privkey(&KeySpec::default()).unwrap();
     let ca = build_ca_cert(
         &key,
         &name,
+        None,
         &val,
     ).unwrap();
 
     let (exts, name, _) = create_server("localhost").unwrap();
 
-    let server_key = build_privkey().unwrap();
+    let server_key = build_privkey(&KeySpec::default()).unwrap();
     let server_csr = build_ca_req(
         &server_key,
         &name,
         |cert_builder| {
             let mut extensions = Stack::<X509Extension>::new()?;
-            run_csr_extensions(&exts, &mut extensions, &cert_builder)?;
+            run_csr_extensions(&exts, server_key.id(), &mut extensions, cert_builder)?;
 
             Ok(())
         }
@@ -92,13 +129,13 @@ fn test_initialization() {
 
     let (exts, name, val) = create_client("localhost").unwrap();
 
-    let client_key = build_privkey().unwrap();
+    let client_key = build_privkey(&KeySpec::default()).unwrap();
     let client_csr = build_ca_req(
         &client_key,
         &name,
         |cert_builder| {
             let mut extensions = Stack::<X509Extension>::new()?;
-            run_csr_extensions(&exts, &mut extensions, &cert_builder)?;
+            run_csr_extensions(&exts, client_key.id(), &mut extensions, cert_builder)?;
 
             Ok(())
         }
@@ -110,8 +147,9 @@ fn test_initialization() {
     let server_cert = build_ca_signed_cert(
         &ca,
         &key,
-        &server_key_pub,
         &server_csr,
+        SubjectKey::Expect(&server_key_pub),
+        &Policy::default(),
         &val,
         |_| {Ok(())}
     ).unwrap();
@@ -119,8 +157,9 @@ fn test_initialization() {
     let client_cert = build_ca_signed_cert(
         &ca,
         &key,
-        &client_key_pub,
         &client_csr,
+        SubjectKey::Expect(&client_key_pub),
+        &Policy::default(),
         &val,
         |_| {Ok(())}
     ).unwrap();
@@ -129,7 +168,7 @@ fn test_initialization() {
 
     let ca_file_name = dbg!(ca_file_name);
 
-    OpenOptions::new().write(true).create_new(true).open(&ca_file_name).unwrap().write(&ca.to_pem().unwrap()).unwrap();
+    OpenOptions::new().write(true).create_new(true).open(&ca_file_name).unwrap().write_all(&ca.to_pem().unwrap()).unwrap();
 
     let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
     acceptor.set_certificate(&server_cert).unwrap();
@@ -157,10 +196,10 @@ fn test_initialization() {
         let mut stream = connector.connect("localhost", stream).unwrap();
 
         let buff = vec![1, 2, 3];
-        stream.write(buff.as_ref()).unwrap();
+        stream.write_all(buff.as_ref()).unwrap();
     });
 
-    for stream in listener.incoming() {
+    if let Some(stream) = listener.incoming().next() {
         let stream = stream.unwrap();
         stream.set_nodelay(true).unwrap();
         //stream.set_nonblocking(true).unwrap();
@@ -169,6 +208,1095 @@ fn test_initialization() {
         let mut buff = Vec::<u8>::with_capacity(128);
         let a = stream.read_to_end(&mut buff).unwrap();
         assert_eq!(a, 3);
-        break;
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_key_specs() {
+    let specs = vec![
+        KeySpec::Rsa(3072),
+        KeySpec::Ec(EcCurve::P256),
+        KeySpec::Ec(EcCurve::P384),
+        KeySpec::Ed25519,
+    ];
+
+    for spec in specs {
+        let (name, val) = create_name_validity("ca").unwrap();
+
+        let key = build_privkey(&spec).unwrap();
+        let ca = build_ca_cert(&key, &name, None, &val).unwrap();
+        assert!(ca.verify(&key).unwrap());
+
+        let (_, name, val) = create_client("client").unwrap();
+
+        let client_key = build_privkey(&spec).unwrap();
+        let client_csr = build_ca_req(&client_key, &name, |_| Ok(())).unwrap();
+        assert!(client_csr.verify(&client_key).unwrap());
+
+        let client_cert = build_ca_signed_cert(
+            &ca,
+            &key,
+            &client_csr,
+            SubjectKey::FromRequest,
+            &Policy::default(),
+            &val,
+            |_| {Ok(())}
+        ).unwrap();
+        assert!(client_cert.verify(&key).unwrap());
+    }
+}
+
+#[test]
+fn test_encrypted_pkey() {
+    let key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+
+    let mut pem = Vec::<u8>::default();
+    pkey_to_file(&mut pem, &key, Some(&KeyEncryption {
+        cipher: Cipher::aes_256_cbc(),
+        passphrase: b"secret",
+    }), Format::Pem).unwrap();
+    assert!(String::from_utf8_lossy(&pem).contains("ENCRYPTED PRIVATE KEY"));
+
+    let loaded = pkey_from_file(&mut pem.as_slice(), Some(b"secret"), Format::Pem).unwrap();
+    assert!(loaded.public_eq(&key));
+
+    match pkey_from_file(&mut pem.as_slice(), Some(b"wrong"), Format::Pem) {
+        Err(LoadError::WrongPassphrase) => {}
+        x => panic!("{:?}", x.map(|_| ())),
+    }
+
+    match pkey_from_file(&mut pem.as_slice(), None, Format::Pem) {
+        Err(LoadError::PassphraseRequired) => {}
+        x => panic!("{:?}", x.map(|_| ())),
+    }
+
+    let mut pem = Vec::<u8>::default();
+    pkey_to_file(&mut pem, &key, None, Format::Pem).unwrap();
+    assert!(pkey_from_file(&mut pem.as_slice(), Some(b"ignored"), Format::Pem).unwrap().public_eq(&key));
+}
+
+#[test]
+fn test_formats() {
+    let (key, ca, _) = create_ca("ca");
+    let csr = build_ca_req(&key, &ca.subject_name().to_owned().unwrap(), |_| Ok(())).unwrap();
+    let crl = build_crl(&ca, &key, &[], &BigNum::from_u32(1).unwrap(), &Asn1Time::days_from_now(7).unwrap()).unwrap();
+
+    for format in [Format::Pem, Format::Der] {
+        let mut buf = Vec::<u8>::default();
+        cert_to_file(&mut buf, &ca, format).unwrap();
+        assert_eq!(buf.starts_with(b"-----BEGIN "), format == Format::Pem);
+
+        for read_format in [format, Format::Auto] {
+            assert_eq!(cert_from_file(&mut buf.as_slice(), read_format).unwrap().to_der().unwrap(), ca.to_der().unwrap());
+        }
+
+        let mut buf = Vec::<u8>::default();
+        csr_to_file(&mut buf, &csr, format).unwrap();
+
+        for read_format in [format, Format::Auto] {
+            assert_eq!(csr_from_file(&mut buf.as_slice(), read_format).unwrap().to_der().unwrap(), csr.to_der().unwrap());
+        }
+
+        let mut buf = Vec::<u8>::default();
+        crl_to_file(&mut buf, &crl, format).unwrap();
+
+        for read_format in [format, Format::Auto] {
+            assert_eq!(crl_from_file(&mut buf.as_slice(), read_format).unwrap().to_der().unwrap(), crl.to_der().unwrap());
+        }
+
+        let mut buf = Vec::<u8>::default();
+        pkey_public_to_file(&mut buf, &key, format).unwrap();
+
+        for read_format in [format, Format::Auto] {
+            assert!(pkey_public_from_file(&mut buf.as_slice(), read_format).unwrap().public_eq(&key));
+        }
+
+        let encryption = KeyEncryption {
+            cipher: Cipher::aes_256_cbc(),
+            passphrase: b"secret",
+        };
+
+        for encryption in [None, Some(&encryption)] {
+            let mut buf = Vec::<u8>::default();
+            pkey_to_file(&mut buf, &key, encryption, format).unwrap();
+
+            for read_format in [format, Format::Auto] {
+                assert!(pkey_from_file(&mut buf.as_slice(), Some(b"secret"), read_format).unwrap().public_eq(&key));
+            }
+        }
+    }
+
+    let mut buf = Vec::<u8>::default();
+    assert!(certs_to_file(&mut buf, &[ca.clone(), ca], Format::Der).is_err());
+
+    // only a PEM header at the start, after optional whitespace, makes a file PEM
+    let (key, ca, _) = create_ca("ca -----BEGIN CERTIFICATE-----");
+    let der = ca.to_der().unwrap();
+    assert_eq!(cert_from_file(&mut der.as_slice(), Format::Auto).unwrap().to_der().unwrap(), der);
+    assert!(matches!(read_object(&der, None), Ok(Object::Certificate(_))));
+
+    let mut pem = b"\r\n\n".to_vec();
+    pkey_to_file(&mut pem, &key, None, Format::Pem).unwrap();
+    assert!(pkey_from_file(&mut pem.as_slice(), None, Format::Auto).unwrap().public_eq(&key));
+}
+
+#[test]
+fn test_errors() {
+    use std::error::Error;
+
+    let (name, val) = create_name_validity("ca").unwrap();
+    let key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let csr = build_ca_req(&key, &name, |_| Ok(())).unwrap();
+    let pem = csr.to_pem().unwrap();
+
+    let err = cert_from_file(&mut pem.as_slice(), Format::Auto).unwrap_err();
+    assert!(matches!(err, LoadError::WrongFormat("certificate", _)));
+    assert_eq!(err.to_string(), "not a valid certificate");
+    assert!(err.source().unwrap().downcast_ref::<ErrorStack>().is_some());
+
+    let err = pkey_from_file(&mut pem.as_slice(), None, Format::Auto).unwrap_err();
+    assert!(matches!(err, LoadError::WrongFormat("private key", _)));
+
+    let err = LoadError::from(std::io::Error::new(std::io::ErrorKind::NotFound, "missing"));
+    assert_eq!(err.source().unwrap().to_string(), "missing");
+
+    let boxed = || -> Result<X509, Box<dyn Error>> {
+        let ca = build_ca_cert(&key, &name, None, &val)?;
+        let mut der = ca.to_der()?;
+        der.truncate(10);
+        Ok(cert_from_file(&mut der.as_slice(), Format::Der)?)
+    };
+    assert_eq!(boxed().unwrap_err().to_string(), "not a valid certificate");
+
+    let app = parser_not_after_before(App::new("asd"));
+    let err = match matches_not_after_before(&app.get_matches_from(vec!["", "--after", "12q"])) {
+        Err(err) => err,
+        Ok(_) => panic!("12q is not a validity"),
+    };
+    assert!(matches!(err, ParseError::Invalid(_)));
+    assert_eq!(err.to_string(), "invalid --after");
+    assert!(err.source().is_none());
+
+    let err = ParseError::from("x".parse::<u32>().unwrap_err());
+    assert!(err.source().unwrap().downcast_ref::<std::num::ParseIntError>().is_some());
+
+    let err = SignError::Policy(vec!["a".to_string(), "b".to_string()]);
+    assert_eq!(err.to_string(), "the request violates the CA policy: a; b");
+}
+
+#[test]
+fn test_validity() {
+    let now = 1_700_000_000;
+
+    assert_eq!(parse_time("0", now), Some(now));
+    assert_eq!(parse_time("90", now), Some(now + 90 * 86400));
+    assert_eq!(parse_time("90d", now), Some(now + 90 * 86400));
+    assert_eq!(parse_time("12h", now), Some(now + 12 * 3600));
+    assert_eq!(parse_time("1d12h15m", now), Some(now + 86400 + 12 * 3600 + 15 * 60));
+    assert_eq!(parse_time("-5m", now), Some(now - 5 * 60));
+    assert_eq!(parse_time("-2", now), Some(now - 2 * 86400));
+    assert_eq!(parse_time("2027-01-01T00:00:00Z", now), Some(1_798_761_600));
+    assert_eq!(parse_time("2027-01-01T02:00:00.25+02:00", now), Some(1_798_761_600));
+    assert_eq!(parse_time("2027-01-01", now), Some(1_798_761_600));
+    assert_eq!(parse_time("270101000000Z", now), Some(1_798_761_600));
+    assert_eq!(parse_time("20270101000000Z", now), Some(1_798_761_600));
+    assert_eq!(parse_time("20500101000000Z", now), Some(2_524_608_000));
+
+    for value in ["", "12x", "d", "2027-02-30", "2027-01-01T25:00:00Z", "271301000000Z", "2027-01-01T00:00:00",
+                  "1\u{e9}234567890Z", "2027-01-01T00:00:0\u{e9}Z"] {
+        assert_eq!(parse_time(value, now), None, "{}", value);
+    }
+
+    let (name, _) = create_name_validity("ca").unwrap();
+    let key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let ca = build_ca_cert(&key, &name, None, &(None, Some(Asn1Time::days_from_now(30).unwrap()))).unwrap();
+
+    let mut val = (None, Some(Asn1Time::days_from_now(10).unwrap()));
+    assert!(!clamp_not_after(&ca, &mut val).unwrap());
+
+    let mut val = (None, Some(Asn1Time::days_from_now(60).unwrap()));
+    assert!(clamp_not_after(&ca, &mut val).unwrap());
+    assert_eq!(asn1_to_unix(val.1.as_ref().unwrap()).unwrap(), asn1_to_unix(ca.not_after()).unwrap());
+
+    let (_, name, _) = create_client("client").unwrap();
+    let csr = build_ca_req(&key, &name, |_| Ok(())).unwrap();
+
+    let val = (Some(Asn1Time::days_from_now(20).unwrap()), Some(Asn1Time::days_from_now(10).unwrap()));
+    assert!(!validity_is_ordered(&val).unwrap());
+
+    match build_ca_signed_cert(&ca, &key, &csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())) {
+        Err(SignError::InvalidValidity) => {}
+        x => panic!("{:?}", x.map(|_| ())),
+    }
+
+    let val = (Some(Asn1Time::from_unix(now).unwrap()), Some(Asn1Time::days_from_now(10).unwrap()));
+    let cert = build_ca_signed_cert(&ca, &key, &csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())).unwrap();
+    assert_eq!(asn1_to_unix(cert.not_before()).unwrap(), now);
+}
+
+#[test]
+fn test_intermediate() {
+    let (name, val) = create_name_validity("root").unwrap();
+
+    let root_key = build_privkey(&KeySpec::default()).unwrap();
+    let root = build_ca_cert(&root_key, &name, None, &val).unwrap();
+
+    let (name, val) = create_name_validity("intermediate").unwrap();
+
+    let inter_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let inter_csr = build_ca_req(&inter_key, &name, |_| Ok(())).unwrap();
+    let inter = build_intermediate_cert(&root, &root_key, &inter_csr, Some(0), None, &IssuerUrls::default(), &val, |_| Ok(())).unwrap();
+
+    assert_eq!(inter.pathlen(), Some(0));
+    assert_eq!(inter.authority_key_id().unwrap().as_slice(), root.subject_key_id().unwrap().as_slice());
+
+    let (_, name, val) = create_server("localhost").unwrap();
+
+    let leaf_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let leaf_csr = build_ca_req(&leaf_key, &name, |_| Ok(())).unwrap();
+    let leaf = build_ca_signed_cert(&inter, &inter_key, &leaf_csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())).unwrap();
+
+    let mut store = X509StoreBuilder::new().unwrap();
+    store.add_cert(root).unwrap();
+    let store = store.build();
+
+    let mut chain = Stack::<X509>::new().unwrap();
+    chain.push(inter).unwrap();
+
+    let mut ctx = X509StoreContext::new().unwrap();
+    assert!(ctx.init(&store, &leaf, &chain, |c| c.verify_cert()).unwrap());
+}
+
+#[test]
+fn test_subject_key() {
+    let (name, val) = create_name_validity("ca").unwrap();
+
+    let key = build_privkey(&KeySpec::default()).unwrap();
+    let ca = build_ca_cert(&key, &name, None, &val).unwrap();
+
+    let (_, name, val) = create_client("client").unwrap();
+
+    let client_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let client_csr = build_ca_req(&client_key, &name, |_| Ok(())).unwrap();
+
+    let other_key = priv_to_pub(&build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap());
+
+    let cert = build_ca_signed_cert(&ca, &key, &client_csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())).unwrap();
+    assert!(cert.public_key().unwrap().public_eq(&client_key));
+
+    match build_ca_signed_cert(&ca, &key, &client_csr, SubjectKey::Expect(&other_key), &Policy::default(), &val, |_| Ok(())) {
+        Err(SignError::KeyMismatch) => {}
+        x => panic!("{:?}", x.map(|_| ())),
+    }
+
+    let cert = build_ca_signed_cert(&ca, &key, &client_csr, SubjectKey::Override(&other_key), &Policy::default(), &val, |_| Ok(())).unwrap();
+    assert!(cert.public_key().unwrap().public_eq(&other_key));
+
+    let mut forged = X509Req::builder().unwrap();
+    forged.set_subject_name(&name).unwrap();
+    forged.set_pubkey(&other_key).unwrap();
+    forged.sign(&client_key, MessageDigest::sha256()).unwrap();
+    let forged = forged.build();
+
+    match build_ca_signed_cert(&ca, &key, &forged, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())) {
+        Err(SignError::BadRequestSignature) => {}
+        x => panic!("{:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_policy() {
+    let (key, ca, val) = create_ca("ca");
+
+    let app = parser_name_builder(parser_csr_extensions(App::new("asd")));
+    let matches = app.get_matches_from(vec![
+        "", "-N", "svc", "-O", "Example",
+        "--ext-server", "--san-dns", "www.example.com", "--san-dns", "evil.org",
+    ]);
+    let exts = matches_csr_extensions(&matches).unwrap();
+    let name = matches_name_builder(&matches).unwrap();
+
+    let svc_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let svc_csr = build_ca_req(&svc_key, &name, |req_builder| {
+        let mut extensions = Stack::<X509Extension>::new()?;
+        run_csr_extensions(&exts, svc_key.id(), &mut extensions, req_builder)?;
+        extensions.push(BasicConstraints::new().critical().ca().build()?)?;
+        req_builder.add_extensions(&extensions)?;
+        Ok(())
+    }).unwrap();
+
+    // the requested CA:TRUE is replaced by the CA's own basic constraints
+    let svc_cert = build_ca_signed_cert(&ca, &key, &svc_csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())).unwrap();
+    let text = String::from_utf8(svc_cert.to_text().unwrap()).unwrap();
+    assert!(text.contains("CA:FALSE") && !text.contains("CA:TRUE"), "{}", text);
+    assert_eq!(san_strings(&svc_cert), vec!["DNS:www.example.com", "DNS:evil.org"]);
+
+    let policy = Policy::parse("
+        # strict
+        extension.basicConstraints = reject
+        subject_fields = CN, C
+        san_domains = example.com
+        max_days = 825
+    ").unwrap();
+    assert_eq!(policy.extension_action(Nid::BASIC_CONSTRAINTS), ExtensionAction::Reject);
+    assert_eq!(policy.extension_action(Nid::SUBJECT_ALT_NAME), ExtensionAction::Copy);
+
+    match build_ca_signed_cert(&ca, &key, &svc_csr, SubjectKey::FromRequest, &policy, &val, |_| Ok(())) {
+        Err(SignError::Policy(violations)) => assert_eq!(violations, vec![
+            "subject field O is not allowed",
+            "DNS:evil.org is outside the allowed domains",
+            "extension basicConstraints is not allowed",
+            "validity of 3650 days exceeds the maximum of 825",
+        ]),
+        x => panic!("{:?}", x.map(|_| ())),
+    }
+
+    match Policy::parse("max_days = soon") {
+        Err(LoadError::Config(msg)) => assert_eq!(msg, "line 1: expected a number of days"),
+        x => panic!("{:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_policy_key_usage() {
+    let (key, ca, val) = create_ca("ca");
+
+    let (_, name, _) = create_server("svc").unwrap();
+    let svc_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let svc_csr = build_ca_req(&svc_key, &name, |req_builder| {
+        let mut extensions = Stack::<X509Extension>::new()?;
+        extensions.push(KeyUsage::new().critical().key_cert_sign().crl_sign().build()?)?;
+        req_builder.add_extensions(&extensions)?;
+        Ok(())
+    }).unwrap();
+
+    match build_ca_signed_cert(&ca, &key, &svc_csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())) {
+        Err(SignError::Policy(violations)) => assert_eq!(violations, vec![
+            "key usage keyCertSign is only allowed in CA certificates",
+            "key usage cRLSign is only allowed in CA certificates",
+        ]),
+        x => panic!("{:?}", x.map(|_| ())),
+    }
+
+    // a CA that lets requests choose their basic constraints takes the key usage as well
+    let policy = Policy::parse("extension.basicConstraints = copy").unwrap();
+    assert!(build_ca_signed_cert(&ca, &key, &svc_csr, SubjectKey::FromRequest, &policy, &val, |_| Ok(())).is_ok());
+
+    let policy = Policy::parse("extension.keyUsage = override").unwrap();
+    let cert = build_ca_signed_cert(&ca, &key, &svc_csr, SubjectKey::FromRequest, &policy, &val, |_| Ok(())).unwrap();
+    let text = String::from_utf8(cert.to_text().unwrap()).unwrap();
+    assert!(!text.contains("Certificate Sign"), "{}", text);
+}
+
+#[test]
+fn test_policy_names() {
+    let (key, ca, val) = create_ca("ca");
+
+    let sign = |policy: &Policy, args: &[&str]| {
+        let app = parser_name_builder(parser_csr_extensions(App::new("asd")));
+        let matches = app.get_matches_from(std::iter::once("").chain(args.iter().copied()));
+        let exts = matches_csr_extensions(&matches).unwrap();
+        let name = matches_name_builder(&matches).unwrap();
+
+        let svc_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+        let svc_csr = csr_with_exts(&svc_key, &name, &exts);
+
+        match build_ca_signed_cert(&ca, &key, &svc_csr, SubjectKey::FromRequest, policy, &val, |_| Ok(())) {
+            Ok(_) => Vec::default(),
+            Err(SignError::Policy(violations)) => violations,
+            Err(x) => panic!("{:?}", x),
+        }
+    };
+
+    let policy = Policy::parse("san_domains = example.com").unwrap();
+
+    assert!(sign(&policy, &[
+        "-N", "www.example.com", "--email-address", "ops@example.com",
+        "--san-uri", "https://api.example.com:8443/v1", "--san-upn", "svc@corp.example.com",
+    ]).is_empty());
+    assert_eq!(sign(&policy, &[
+        "-N", "www.evil.org", "--email-address", "ops@evil.org",
+        "--san-uri", "https://user@evil.org/", "--san-uri", "urn:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6",
+        "--san-upn", "svc@evil.org", "--san-ip", "10.0.0.1",
+    ]), vec![
+        "subject CN www.evil.org is outside the allowed domains",
+        "subject emailAddress ops@evil.org is outside the allowed domains",
+        "IP:10.0.0.1 is outside the allowed networks",
+        "URI:https://user@evil.org/ is outside the allowed domains",
+        "URI:urn:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6 has no host to check against the allowed domains",
+        "UPN:svc@evil.org is outside the allowed domains",
+    ]);
+    // a common name that is not a host name is left alone
+    assert!(sign(&policy, &["-N", "payments service"]).is_empty());
+
+    // IP addresses, also as URI hosts, must be in one of the listed networks
+    let policy = Policy::parse("
+        san_domains = example.com  # internal only
+        san_ips = 10.0.0.0/8, fd00::/8
+    ").unwrap();
+    assert!(sign(&policy, &["-N", "api", "--san-ip", "10.1.2.3", "--san-ip", "fd00::1", "--san-uri", "https://10.0.0.1/"]).is_empty());
+    assert_eq!(sign(&policy, &["-N", "api", "--san-ip", "192.168.0.1", "--san-uri", "https://[2001:db8::1]/"]), vec![
+        "IP:192.168.0.1 is outside the allowed networks",
+        "URI:https://[2001:db8::1]/ is outside the allowed networks",
+    ]);
+
+    // without san_domains only the addresses are restricted
+    let policy = Policy::parse("san_ips = 10.0.0.0/8").unwrap();
+    assert!(sign(&policy, &["-N", "www.evil.org", "--san-dns", "evil.org", "--san-ip", "10.0.0.1"]).is_empty());
+    assert!(sign(&Policy::default(), &["-N", "api", "--san-ip", "192.168.0.1"]).is_empty());
+
+    match Policy::parse("san_ips = 10.0.0.0/33") {
+        Err(LoadError::Config(msg)) => assert_eq!(msg, "line 1: expected IP networks"),
+        x => panic!("{:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_inspect() {
+    let (key, ca, _) = create_ca("ca");
+
+    let (exts, name, val) = create_server("web \"1\"").unwrap();
+    let server_key = build_privkey(&KeySpec::default()).unwrap();
+    let server_csr = csr_with_exts(&server_key, &name, &exts);
+    let server_cert = build_ca_signed_cert(&ca, &key, &server_csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())).unwrap();
+
+    for bytes in &[server_cert.to_pem().unwrap(), server_cert.to_der().unwrap()] {
+        let report = describe(&read_object(bytes, None).unwrap()).unwrap();
+
+        assert_eq!(report.get("type"), Some(&Value::Text("certificate".to_string())));
+        assert_eq!(report.get("issuer"), Some(&Value::Text("/CN=ca".to_string())));
+        assert_eq!(report.get("ca"), Some(&Value::Bool(false)));
+        assert_eq!(report.get("san"), Some(&Value::List(vec!["DNS:localhost".to_string()])));
+        assert_eq!(report.get("extended_key_usage"), Some(&Value::List(vec!["serverAuth".to_string()])));
+        assert_eq!(report.get("key_bits"), Some(&Value::Number(2048)));
+    }
+
+    let report = describe(&read_object(&ca.to_pem().unwrap(), None).unwrap()).unwrap();
+    assert_eq!(report.get("ca"), Some(&Value::Bool(true)));
+    assert_eq!(report.get("key_usage"), Some(&Value::List(vec!["keyCertSign".to_string(), "cRLSign".to_string()])));
+    assert_eq!(report.get("key_curve"), Some(&Value::Text("prime256v1".to_string())));
+
+    let report = describe(&read_object(&server_csr.to_der().unwrap(), None).unwrap()).unwrap();
+    assert_eq!(report.get("type"), Some(&Value::Text("certificate request".to_string())));
+    assert_eq!(report.get("signature_valid"), Some(&Value::Bool(true)));
+    assert!(report.to_json().contains(r#""subject": "/CN=web \"1\"","#), "{}", report.to_json());
+
+    // a key and the certificate issued for it share the public key digest
+    let key_report = describe(&read_object(&server_key.private_key_to_pem_pkcs8().unwrap(), None).unwrap()).unwrap();
+    let cert_report = describe(&read_object(&server_cert.to_pem().unwrap(), None).unwrap()).unwrap();
+    assert_eq!(key_report.get("type"), Some(&Value::Text("private key".to_string())));
+    assert_eq!(key_report.get("key_sha256"), cert_report.get("key_sha256"));
+
+    match read_object(b"not a certificate", None) {
+        Err(LoadError::UnknownFormat) => {}
+        x => panic!("{:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_verify() {
+    let (root_key, root, _) = create_ca("root");
+
+    let (name, val) = create_name_validity("intermediate").unwrap();
+    let inter_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let inter_csr = build_ca_req(&inter_key, &name, |_| Ok(())).unwrap();
+    let inter = build_intermediate_cert(&root, &root_key, &inter_csr, None, None, &IssuerUrls::default(), &val, |_| Ok(())).unwrap();
+
+    let (exts, name, val) = create_server("localhost").unwrap();
+    let leaf_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let leaf_csr = csr_with_exts(&leaf_key, &name, &exts);
+    let leaf = build_ca_signed_cert(&inter, &inter_key, &leaf_csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())).unwrap();
+
+    let roots = vec![root];
+    let inters = vec![inter.clone()];
+
+    let options = VerifyOptions {
+        purpose: Some(Purpose::Server),
+        host: Some("localhost".to_string()),
+        ip: None,
+    };
+
+    let res = verify_chain(&leaf, &roots, &inters, &options).unwrap();
+    assert!(res.is_valid(), "{:?}", res.errors);
+    assert_eq!(
+        res.path.iter().map(|x| name_to_string(x.subject_name())).collect::<Vec<_>>(),
+        vec!["/CN=localhost", "/CN=intermediate", "/CN=root"],
+    );
+
+    let res = verify_chain(&leaf, &roots, &[], &VerifyOptions::default()).unwrap();
+    assert_eq!(res.errors, vec!["unable to get local issuer certificate at depth 0 (/CN=localhost)"]);
+
+    let options = VerifyOptions {
+        purpose: Some(Purpose::Client),
+        host: Some("example.com".to_string()),
+        ip: Some("127.0.0.1".parse().unwrap()),
+    };
+
+    let res = verify_chain(&leaf, &roots, &inters, &options).unwrap();
+    assert_eq!(res.errors, vec![
+        "not valid for client use: unsuitable certificate purpose at depth 0 (/CN=localhost)",
+        "host name example.com does not match the certificate",
+        "IP address 127.0.0.1 does not match the certificate",
+    ]);
+
+    let expired = (Some(Asn1Time::from_unix(1_000_000_000).unwrap()), Some(Asn1Time::from_unix(1_100_000_000).unwrap()));
+    let old = build_ca_signed_cert(&inter, &inter_key, &leaf_csr, SubjectKey::FromRequest, &Policy::default(), &expired, |_| Ok(())).unwrap();
+
+    let res = verify_chain(&old, &roots, &inters, &VerifyOptions::default()).unwrap();
+    assert_eq!(res.errors, vec!["certificate has expired at depth 0 (/CN=localhost)"]);
+}
+
+#[test]
+fn test_pkcs12() {
+    let (name, val) = create_name_validity("ca").unwrap();
+    let key = build_privkey(&KeySpec::default()).unwrap();
+    let ca = build_ca_cert(&key, &name, None, &val).unwrap();
+
+    let (_, name, val) = create_client("client").unwrap();
+    let client_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let client_csr = build_ca_req(&client_key, &name, |_| Ok(())).unwrap();
+    let client_cert = build_ca_signed_cert(&ca, &key, &client_csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())).unwrap();
+
+    let pkcs12 = build_pkcs12(&client_key, &client_cert, std::slice::from_ref(&ca), "client", "secret").unwrap();
+
+    let mut der = Vec::<u8>::default();
+    pkcs12_to_file(&mut der, &pkcs12).unwrap();
+
+    let contents = pkcs12_from_file(&mut der.as_slice(), "secret").unwrap();
+    assert!(contents.pkey.unwrap().public_eq(&client_key));
+    assert_eq!(contents.cert.unwrap().to_der().unwrap(), client_cert.to_der().unwrap());
+    assert_eq!(contents.chain.len(), 1);
+    assert_eq!(contents.chain[0].to_der().unwrap(), ca.to_der().unwrap());
+
+    match pkcs12_from_file(&mut der.as_slice(), "wrong") {
+        Err(LoadError::WrongPassphrase) => {}
+        x => panic!("{:?}", x.map(|_| ())),
+    }
+
+    match build_pkcs12(&key, &client_cert, &[], "client", "secret") {
+        Err(LoadError::KeyMismatch) => {}
+        x => panic!("{:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_chain() {
+    let dir = tempdir().unwrap();
+
+    let (root_key, root, val) = create_ca("root");
+
+    let (name, _) = create_name_validity("intermediate").unwrap();
+    let key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let csr = build_ca_req(&key, &name, |_| Ok(())).unwrap();
+    let ca = build_intermediate_cert(&root, &root_key, &csr, None, None, &IssuerUrls::default(), &val, |_| Ok(())).unwrap();
+
+    let db = CaDatabase::create(dir.path().join("ca")).unwrap();
+    assert_eq!(db.chain(&ca).unwrap().len(), 1);
+
+    let mut pem = Vec::<u8>::default();
+    certs_to_file(&mut pem, &[root.clone(), ca.clone()], Format::Pem).unwrap();
+    std::fs::write(dir.path().join("ca/chain.pem"), &pem).unwrap();
+    assert!(db.chain(&ca).is_err());
+
+    let mut pem = Vec::<u8>::default();
+    certs_to_file(&mut pem, &[ca.clone(), root.clone()], Format::Pem).unwrap();
+    std::fs::write(dir.path().join("ca/chain.pem"), &pem).unwrap();
+    let chain = db.chain(&ca).unwrap();
+    assert_eq!(chain.len(), 2);
+
+    let (_, name, val) = create_client("client").unwrap();
+    let client_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let client_csr = build_ca_req(&client_key, &name, |_| Ok(())).unwrap();
+    let client_cert = build_ca_signed_cert(&ca, &key, &client_csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())).unwrap();
+
+    let mut fullchain = Vec::<u8>::default();
+    fullchain_to_file(&mut fullchain, &client_cert, &chain).unwrap();
+    let certs = certs_from_file(&mut fullchain.as_slice(), Format::Auto).unwrap();
+    assert_eq!(certs.len(), 3);
+    assert_eq!(certs[0].to_der().unwrap(), client_cert.to_der().unwrap());
+
+    let verification = verify_chain(&certs[0], std::slice::from_ref(&root), &certs[1..], &VerifyOptions::default()).unwrap();
+    assert!(verification.is_valid(), "{:?}", verification.errors);
+
+    let mut bundle = Vec::<u8>::default();
+    bundle_to_file(&mut bundle, &client_key, &client_cert, &chain).unwrap();
+    assert!(pkey_from_file(&mut bundle.as_slice(), None, Format::Pem).unwrap().public_eq(&client_key));
+    assert_eq!(certs_from_file(&mut bundle.as_slice(), Format::Pem).unwrap().len(), 3);
+
+    match bundle_to_file(&mut Vec::<u8>::default(), &key, &client_cert, &chain) {
+        Err(LoadError::KeyMismatch) => {}
+        x => panic!("{:?}", x),
+    }
+}
+
+#[test]
+fn test_issue() {
+    let (key, ca, _) = create_ca("ca");
+
+    let (exts, name, val) = create_server("localhost").unwrap();
+
+    let (server_key, server_cert) = build_issued_cert(
+        &ca,
+        &key,
+        &KeySpec::Ec(EcCurve::P384),
+        &name,
+        &Policy::default(),
+        &val,
+        add_csr_extensions(&exts, Id::EC),
+    ).unwrap();
+
+    assert!(server_cert.verify(&key).unwrap());
+    assert!(server_cert.public_key().unwrap().public_eq(&server_key));
+    assert_eq!(server_key.bits(), 384);
+    assert_eq!(san_strings(&server_cert), vec!["DNS:localhost".to_string()]);
+
+    let options = VerifyOptions { purpose: Some(Purpose::Server), host: Some("localhost".to_string()), ip: None };
+    assert!(verify_chain(&server_cert, std::slice::from_ref(&ca), &[], &options).unwrap().is_valid());
+}
+
+#[test]
+fn test_crl() {
+    let dir = tempdir().unwrap();
+
+    let (key, ca, _) = create_ca("ca");
+
+    let (_, name, val) = create_client("client").unwrap();
+
+    let client_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let client_csr = build_ca_req(&client_key, &name, |_| Ok(())).unwrap();
+    let client_cert = build_ca_signed_cert(&ca, &key, &client_csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())).unwrap();
+
+    let db = CaDatabase::create(dir.path().join("ca")).unwrap();
+
+    let serial = client_cert.serial_number().to_bn().unwrap();
+    db.revoke(&serial, Some(&client_cert), Some(RevocationReason::KeyCompromise)).unwrap();
+    assert!(db.revoke(&serial, None, None).is_err());
+    db.revoke(&BigNum::from_u32(0xabc).unwrap(), None, None).unwrap();
+
+    assert_eq!(db.next_crl_number().unwrap(), BigNum::from_u32(1).unwrap());
+
+    let crl = build_crl(
+        &ca,
+        &key,
+        &db.revoked().unwrap(),
+        &db.next_crl_number().unwrap(),
+        &Asn1Time::days_from_now(7).unwrap(),
+    ).unwrap();
+
+    assert!(crl.verify(&key).unwrap());
+    assert_eq!(crl.get_revoked().unwrap().len(), 2);
+
+    let (_, number) = crl.extension::<CrlNumber>().unwrap().unwrap();
+    assert_eq!(number.to_bn().unwrap(), BigNum::from_u32(2).unwrap());
+
+    match crl.get_by_cert(&client_cert) {
+        CrlStatus::Revoked(entry) => {
+            let (_, reason) = entry.extension::<ReasonCode>().unwrap().unwrap();
+            assert_eq!(reason.get_i64().unwrap(), 1);
+        }
+        _ => panic!("client certificate is not revoked"),
+    }
+}
+
+#[test]
+fn test_database() {
+    let dir = tempdir().unwrap();
+
+    let (key, ca, _) = create_ca("ca");
+
+    let (exts, name, val) = create_server("localhost").unwrap();
+
+    let server_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let server_csr = csr_with_exts(&server_key, &name, &exts);
+    let server_cert = build_ca_signed_cert(&ca, &key, &server_csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())).unwrap();
+
+    assert!(matches!(CaDatabase::open(dir.path().join("ca")), Err(LoadError::Database(_))));
+
+    let db = CaDatabase::create(dir.path().join("ca")).unwrap();
+    let record = db.insert(&server_cert).unwrap();
+    assert!(db.insert(&server_cert).is_err());
+
+    assert_eq!(record.subject, "/CN=localhost");
+    assert_eq!(record.current_status().unwrap(), Status::Valid);
+
+    let serial = server_cert.serial_number().to_bn().unwrap();
+    let found = db.find(&serial).unwrap().unwrap();
+    let stored = db.cert(&found).unwrap().unwrap();
+    assert_eq!(stored.to_der().unwrap(), server_cert.to_der().unwrap());
+    assert_eq!(san_strings(&stored), vec!["DNS:localhost".to_string()]);
+
+    db.revoke(&serial, None, Some(RevocationReason::Superseded)).unwrap();
+
+    let records = CaDatabase::open(dir.path().join("ca")).unwrap().records().unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].status, Status::Revoked);
+    assert_eq!(records[0].revoked.as_ref().unwrap().1, Some(RevocationReason::Superseded));
+
+    let (_, name, val) = create_client("tab\there\nnewline\\t").unwrap();
+    let client_csr = build_ca_req(&server_key, &name, |_| Ok(())).unwrap();
+    let client_cert = build_ca_signed_cert(&ca, &key, &client_csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())).unwrap();
+    db.insert(&client_cert).unwrap();
+
+    let records = db.records().unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[1].subject, "/CN=tab\there\nnewline\\t");
+}
+
+#[test]
+fn test_extended_key_usage() {
+    let app = parser_name_builder(parser_csr_extensions(App::new("asd")));
+    let matches = app.get_matches_from(vec![
+        "", "-N", "peer", "--ext-server", "--ext-client", "--eku", "1.3.6.1.5.5.7.3.8",
+    ]);
+    let exts = matches_csr_extensions(&matches).unwrap();
+    let name = matches_name_builder(&matches).unwrap();
+
+    let (key, ca, val) = create_ca("ca");
+
+    let peer_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let peer_csr = csr_with_exts(&peer_key, &name, &exts);
+    let peer_cert = build_ca_signed_cert(&ca, &key, &peer_csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())).unwrap();
+
+    let text = String::from_utf8(peer_cert.to_text().unwrap()).unwrap();
+    assert!(text.contains("TLS Web Server Authentication, TLS Web Client Authentication, Time Stamping"));
+    assert!(text.contains("X509v3 Key Usage: critical\n                Digital Signature\n"));
+    assert_eq!(text.matches("X509v3 Key Usage").count(), 1);
+    assert!(!text.contains("Netscape"));
+
+    let rsa_key = build_privkey(&KeySpec::Rsa(2048)).unwrap();
+    let rsa_csr = csr_with_exts(&rsa_key, &name, &exts);
+    let rsa_cert = build_ca_signed_cert(&ca, &key, &rsa_csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())).unwrap();
+
+    let text = String::from_utf8(rsa_cert.to_text().unwrap()).unwrap();
+    assert!(text.contains("X509v3 Key Usage: critical\n                Digital Signature, Key Encipherment\n"));
+
+    let app = parser_csr_extensions(App::new("asd"));
+    let matches = app.get_matches_from(vec!["", "--eku", "serverAuth; rm"]);
+    assert!(matches_csr_extensions(&matches).is_err());
+}
+
+#[test]
+fn test_subject_alt_names() {
+    let app = parser_name_builder(parser_csr_extensions(App::new("asd")));
+    let matches = app.get_matches_from(vec![
+        "", "-N", "svc",
+        "--san-dns", "svc.internal", "--san-dns", "*.svc.internal",
+        "--san-ip", "10.0.0.1", "--san-ip", "fd00::1",
+        "--san-email", "ops@example.com",
+        "--san-uri", "spiffe://example.org/ns/default/sa/svc",
+        "--san-upn", "svc@corp.example.com",
+    ]);
+    let exts = matches_csr_extensions(&matches).unwrap();
+    let name = matches_name_builder(&matches).unwrap();
+
+    let (key, ca, val) = create_ca("ca");
+
+    let svc_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let svc_csr = csr_with_exts(&svc_key, &name, &exts);
+    let svc_cert = build_ca_signed_cert(&ca, &key, &svc_csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(())).unwrap();
+
+    assert_eq!(san_strings(&svc_cert), vec![
+        "DNS:svc.internal",
+        "DNS:*.svc.internal",
+        "IP:10.0.0.1",
+        "IP:fd00::1",
+        "email:ops@example.com",
+        "URI:spiffe://example.org/ns/default/sa/svc",
+        "UPN:svc@corp.example.com",
+    ]);
+
+    let text = String::from_utf8(svc_cert.to_text().unwrap()).unwrap();
+    assert!(text.contains("othername: UPN::svc@corp.example.com"), "{}", text);
+
+    let options = VerifyOptions { purpose: None, host: Some("a.svc.internal".to_string()), ip: Some("fd00::1".parse().unwrap()) };
+    let res = verify_chain(&svc_cert, std::slice::from_ref(&ca), &[], &options).unwrap();
+    assert!(res.is_valid(), "{:?}", res.errors);
+
+    for (kind, value) in &[
+        ("dns", "-bad.example.com"),
+        ("dns", "a..b"),
+        ("ip", "10.0.0.256"),
+        ("email", "no-at-sign"),
+        ("uri", "not a uri"),
+        ("upn", "@corp"),
+    ] {
+        assert!(SanEntry::parse(kind, value).is_err(), "{} {}", kind, value);
+    }
+}
+
+#[test]
+fn test_output_file() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("cert.pem");
+    let count = || std::fs::read_dir(dir.path()).unwrap().count();
+
+    // nothing appears at the path until the output is committed
+    let mut file = OutputFile::create(&path, Overwrite::Refuse, Access::Shared).unwrap();
+    file.write_all(b"first").unwrap();
+    assert!(!path.exists());
+    file.commit().unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"first");
+
+    assert_eq!(OutputFile::create(&path, Overwrite::Refuse, Access::Shared).err().unwrap().kind(), std::io::ErrorKind::AlreadyExists);
+
+    // an output dropped before the commit leaves the old contents alone
+    let mut file = OutputFile::create(&path, Overwrite::Replace, Access::Shared).unwrap();
+    file.write_all(b"half").unwrap();
+    drop(file);
+    assert_eq!(std::fs::read(&path).unwrap(), b"first");
+    assert_eq!(count(), 1);
+
+    let mut file = OutputFile::create(&path, Overwrite::Replace, Access::Shared).unwrap();
+    file.write_all(b"second").unwrap();
+    file.commit().unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"second");
+
+    // a key replacing a shared file does not inherit its permissions
+    let mut file = OutputFile::create(&path, Overwrite::Backup, Access::Owner).unwrap();
+    file.write_all(b"third").unwrap();
+    file.commit().unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"third");
+    assert_eq!(std::fs::read(backup_path(&path)).unwrap(), b"second");
+    assert_eq!(open_permissions(&path).unwrap(), 0);
+    assert_eq!(count(), 2);
+
+    create_private_dir(dir.path().join("ca/certs")).unwrap();
+    assert_eq!(open_permissions(dir.path().join("ca")).unwrap(), 0);
+    assert_eq!(open_permissions(dir.path().join("ca/certs")).unwrap(), 0);
+}
+
+#[test]
+fn test_subject_names() {
+    let app = parser_name_builder(App::new("asd"));
+    let matches = app.get_matches_from(vec![
+        "", "-N", "host", "-U", "Web", "-O", "Example", "-U", "Ops", "-C", "GB", "-L", "London",
+        "--domain-component", "com", "--domain-component", "example", "--email-address", "ops@example.com",
+    ]);
+    let name = matches_name_builder(&matches).unwrap();
+    assert_eq!(
+        name_to_string(&name),
+        "/DC=com/DC=example/C=GB/L=London/O=Example/OU=Web/OU=Ops/CN=host/emailAddress=ops@example.com",
+    );
+
+    let app = parser_name_builder(App::new("asd"));
+    let matches = app.get_matches_from(vec!["", "--subject", r"/C=GB/O=Example\/Sub/OU=Web+OU=Ops/CN=host"]);
+    let name = matches_name_builder(&matches).unwrap();
+    assert_eq!(name_to_string(&name), "/C=GB/O=Example/Sub/OU=Web/OU=Ops/CN=host");
+
+    // the same name in RFC 4514 order, with an escaped comma and a hex-escaped UTF-8 character
+    let rfc = build_name(&parse_subject(r"CN=host, OU=Web+OU=Ops, O=Example\2C Ltd, L=Z\C3\BCrich, C=CH").unwrap()).unwrap();
+    assert_eq!(name_to_string(&rfc), "/C=CH/L=Zürich/O=Example, Ltd/OU=Web/OU=Ops/CN=host");
+    let openssl = build_name(&parse_subject(r"/C=CH/L=Zürich/O=Example, Ltd/OU=Web+OU=Ops/CN=host").unwrap()).unwrap();
+    assert_eq!(rfc.to_der().unwrap(), openssl.to_der().unwrap());
+
+    // a multi-valued RDN differs from two RDNs with one attribute each
+    let split = build_name(&parse_subject("/C=CH/L=Zürich/O=Example, Ltd/OU=Web/OU=Ops/CN=host").unwrap()).unwrap();
+    assert_ne!(split.to_der().unwrap(), openssl.to_der().unwrap());
+
+    for subject in &["/CN=host/XYZ=1", "/CN=", "/CN=host/O", r"CN=host\", ""] {
+        assert!(matches!(parse_subject(subject), Err(ParseError::Name(_))), "{}", subject);
+    }
+
+    // types built by hand rather than parsed are refused, not passed on to OpenSSL
+    let rdns = vec![vec![("C\0N".to_string(), "host".to_string())]];
+    assert!(matches!(build_name(&rdns), Err(NameError::InvalidType(_))));
+    assert!(matches!(validate_name(&rdns), Err(ParseError::Name(_))));
+
+    let app = parser_name_builder(App::new("asd"));
+    assert!(app.get_matches_from_safe(vec!["", "--subject", "/CN=host", "-N", "other"]).is_err());
+}
+
+#[test]
+fn test_name_validation() {
+    let subject = |x: &str| validate_name(&parse_subject(x).unwrap());
+
+    assert!(subject("/C=GB/ST=Zürich/O=Example/CN=host/serialNumber=AB-1234/emailAddress=ops@example.com").is_ok());
+    assert!(subject(&format!("/CN={}", "é".repeat(64))).is_ok());
+
+    assert!(matches!(subject(&format!("/CN={}", "é".repeat(65))), Err(ParseError::NameTooLong("CN", 64))));
+    assert!(matches!(subject(&format!("commonName={}", "x".repeat(65))), Err(ParseError::NameTooLong("CN", 64))));
+    assert!(matches!(subject(&format!("/L={}", "x".repeat(129))), Err(ParseError::NameTooLong("L", 128))));
+
+    for country in &["GBR", "G", "gb", "G1"] {
+        assert!(matches!(subject(&format!("/C={}/CN=host", country)), Err(ParseError::NameInvalid("C", _))), "{}", country);
+    }
+    assert!(matches!(subject("/2.5.4.6=gb/CN=host"), Err(ParseError::NameInvalid("C", _))));
+    assert!(matches!(subject("/CN=host/serialNumber=A_1"), Err(ParseError::NameInvalid("serialNumber", _))));
+    assert!(matches!(subject("/DC=bücher/CN=host"), Err(ParseError::NameInvalid("DC", _))));
+
+    // the flags go through the same checks
+    let app = parser_name_builder(App::new("asd"));
+    let matches = app.get_matches_from(vec!["", "-N", "host", "-C", "United Kingdom"]);
+    let err = matches_name_builder(&matches).err().unwrap();
+    assert_eq!(err.to_string(), "invalid subject: C must be a two-letter ISO 3166 code in capitals, e.g. GB");
+
+    let app = parser_name_builder(App::new("asd"));
+    let matches = app.get_matches_from(vec!["", "-N", ""]);
+    assert!(matches!(matches_name_builder(&matches), Err(ParseError::NameInvalid("CN", _))));
+}
+
+#[test]
+fn test_name_constraints() {
+    let (root_key, root, _) = create_ca("root");
+
+    let constraints = NameConstraints {
+        permitted: vec![Subtree::parse("DNS:*.payments.internal").unwrap(), Subtree::parse("IP:10.20.7.1/16").unwrap()],
+        excluded: vec![Subtree::parse("DNS:admin.payments.internal").unwrap()],
+    };
+    assert_eq!(constraints.permitted, vec![
+        Subtree::Dns(".payments.internal".to_string()),
+        Subtree::Ip("10.20.0.0".parse().unwrap(), 16),
+    ]);
+    assert!(Subtree::parse("IP:10.0.0.0/33").is_err());
+    assert!(Subtree::parse("dirName:x").is_err());
+
+    let (name, val) = create_name_validity("payments").unwrap();
+    let inter_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let inter_csr = build_ca_req(&inter_key, &name, |_| Ok(())).unwrap();
+    let inter = build_intermediate_cert(&root, &root_key, &inter_csr, Some(0), Some(&constraints), &IssuerUrls::default(), &val, |_| Ok(())).unwrap();
+
+    let der = cert_extension_data(&inter, Nid::NAME_CONSTRAINTS).unwrap();
+    assert_eq!(NameConstraints::from_der(der).unwrap(), constraints);
+
+    let sign = |args: &[&str]| {
+        let app = parser_name_builder(parser_csr_extensions(App::new("asd")));
+        let matches = app.get_matches_from([&[""][..], args].concat());
+        let exts = matches_csr_extensions(&matches).unwrap();
+        let key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+        let csr = csr_with_exts(&key, &matches_name_builder(&matches).unwrap(), &exts);
+        build_ca_signed_cert(&inter, &inter_key, &csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(()))
+    };
+
+    let leaf = sign(&["-N", "api", "--san-dns", "api.payments.internal", "--san-dns", "*.api.payments.internal", "--san-ip", "10.20.1.5"]).unwrap();
+
+    // OpenSSL enforces the same constraints when verifying
+    let res = verify_chain(&leaf, std::slice::from_ref(&root), std::slice::from_ref(&inter), &VerifyOptions::default()).unwrap();
+    assert!(res.is_valid(), "{:?}", res.errors);
+
+    let violations = |args: &[&str]| match sign(args) {
+        Err(SignError::NameConstraints(x)) => x,
+        x => panic!("{:?}", x.map(|_| ())),
+    };
+
+    assert_eq!(violations(&["-N", "api", "--san-dns", "payments.internal", "--san-ip", "10.30.0.1"]), vec![
+        "DNS:payments.internal is outside the permitted name constraints",
+        "IP:10.30.0.1 is outside the permitted name constraints",
+    ]);
+    assert_eq!(violations(&["-N", "api", "--san-dns", "admin.payments.internal"]), vec![
+        "DNS:admin.payments.internal is excluded by the name constraint DNS:admin.payments.internal",
+    ]);
+    // without DNS names in the subjectAltName a host name in the common name is checked
+    assert_eq!(violations(&["-N", "www.example.com"]), vec![
+        "DNS:www.example.com is outside the permitted name constraints",
+    ]);
+
+    // names of a type without permitted subtrees are not restricted
+    assert!(sign(&["-N", "api", "--san-email", "ops@example.com"]).is_ok());
+
+    // unless the constraints cannot be evaluated for their type
+    assert_eq!(violations(&["-N", "api", "--san-upn", "api@payments.internal"]), vec![
+        "UPN:api@payments.internal cannot be checked against the name constraints",
+    ]);
+
+    let subordinate = |subtrees: &[&str]| {
+        let child = NameConstraints { permitted: subtrees.iter().map(|x| Subtree::parse(x).unwrap()).collect(), excluded: Vec::default() };
+        build_intermediate_cert(&inter, &inter_key, &inter_csr, None, Some(&child), &IssuerUrls::default(), &val, |_| Ok(()))
+    };
+
+    assert!(subordinate(&["DNS:api.payments.internal", "DNS:.web.payments.internal", "IP:10.20.7.0/24", "email:example.com"]).is_ok());
+
+    match subordinate(&["DNS:.payments.internal", "DNS:payments.internal", "DNS:*.admin.payments.internal", "IP:10.0.0.0/8"]) {
+        Err(SignError::NameConstraints(x)) => assert_eq!(x, vec![
+            "name constraint DNS:payments.internal is outside the permitted name constraints",
+            "name constraint DNS:.admin.payments.internal is excluded by the name constraint DNS:admin.payments.internal",
+            "name constraint IP:10.0.0.0/8 is outside the permitted name constraints",
+        ]),
+        x => panic!("{:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_inherited_name_constraints() {
+    let (name, val) = create_name_validity("root").unwrap();
+    let root_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let root_constraints = NameConstraints {
+        permitted: vec![Subtree::parse("DNS:.payments.internal").unwrap(), Subtree::parse("IP:10.20.0.0/16").unwrap()],
+        excluded: vec![Subtree::parse("DNS:admin.payments.internal").unwrap()],
+    };
+    let root = build_ca_cert(&root_key, &name, Some(&root_constraints), &val).unwrap();
+
+    let intermediate = |constraints: Option<&NameConstraints>| {
+        let (name, val) = create_name_validity("intermediate").unwrap();
+        let key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+        let csr = build_ca_req(&key, &name, |_| Ok(())).unwrap();
+        let cert = build_intermediate_cert(&root, &root_key, &csr, None, constraints, &IssuerUrls::default(), &val, |_| Ok(())).unwrap();
+        let constraints = NameConstraints::from_der(cert_extension_data(&cert, Nid::NAME_CONSTRAINTS).unwrap()).unwrap();
+
+        (key, cert, constraints)
+    };
+
+    // an intermediate without constraints of its own gets those of the root
+    let (inter_key, inter, constraints) = intermediate(None);
+    assert_eq!(constraints, root_constraints);
+
+    let (_, name, val) = create_client("client").unwrap();
+    let leaf_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let sign = |san: &str| {
+        let exts = vec![CsrExt::San(vec![SanEntry::parse("dns", san).unwrap()])];
+        let csr = csr_with_exts(&leaf_key, &name, &exts);
+        build_ca_signed_cert(&inter, &inter_key, &csr, SubjectKey::FromRequest, &Policy::default(), &val, |_| Ok(()))
+    };
+
+    match sign("evil.com") {
+        Err(SignError::NameConstraints(x)) => assert_eq!(x, vec!["DNS:evil.com is outside the permitted name constraints"]),
+        x => panic!("{:?}", x.map(|_| ())),
+    }
+
+    let leaf = sign("api.payments.internal").unwrap();
+    let res = verify_chain(&leaf, std::slice::from_ref(&root), std::slice::from_ref(&inter), &VerifyOptions::default()).unwrap();
+    assert!(res.is_valid(), "{:?}", res.errors);
+
+    // narrowing one type keeps the root's subtrees of the other types
+    let narrowed = NameConstraints { permitted: vec![Subtree::parse("DNS:.api.payments.internal").unwrap()], excluded: Vec::default() };
+    let (_, _, constraints) = intermediate(Some(&narrowed));
+    assert_eq!(constraints.permitted, vec![narrowed.permitted[0].clone(), root_constraints.permitted[1].clone()]);
+    assert_eq!(constraints.excluded, root_constraints.excluded);
+}
+
+#[test]
+fn test_issuer_urls() {
+    let policy = Policy::parse("
+        ca_issuers = http://pki.example.com/ca.crt
+        ocsp = http://ocsp.example.com, http://ocsp2.example.com
+        crl_distribution_points = http://pki.example.com/ca.crl, ldap://ldap.example.com/cn=ca
+    ").unwrap();
+    assert_eq!(policy.urls.ocsp, vec!["http://ocsp.example.com", "http://ocsp2.example.com"]);
+    // a # only starts a comment at the start of a line or after whitespace
+    let urls = Policy::parse("#ocsp = http://ocsp.example.com\nca_issuers = http://pki.example.com/ca.crt#v2 # current\n").unwrap().urls;
+    assert_eq!(urls.ca_issuers, vec!["http://pki.example.com/ca.crt#v2"]);
+    assert!(urls.ocsp.is_empty());
+    assert!(matches!(Policy::parse("ocsp = ocsp.example.com"), Err(LoadError::Config(_))));
+
+    let (root_key, root, _) = create_ca("root");
+    assert!(!String::from_utf8(root.to_text().unwrap()).unwrap().contains("OCSP"));
+
+    let (name, val) = create_name_validity("intermediate").unwrap();
+    let inter_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let inter_csr = build_ca_req(&inter_key, &name, |_| Ok(())).unwrap();
+    let inter = build_intermediate_cert(&root, &root_key, &inter_csr, None, None, &policy.urls, &val, |_| Ok(())).unwrap();
+
+    let (_, name, val) = create_server("localhost").unwrap();
+    let leaf_key = build_privkey(&KeySpec::Ec(EcCurve::P256)).unwrap();
+    let leaf_csr = build_ca_req(&leaf_key, &name, |_| Ok(())).unwrap();
+    let leaf = build_ca_signed_cert(&inter, &inter_key, &leaf_csr, SubjectKey::FromRequest, &policy, &val, |_| Ok(())).unwrap();
+
+    for cert in &[inter, leaf] {
+        let responders = cert.ocsp_responders().unwrap();
+        assert_eq!(responders.iter().map(|x| x.to_string()).collect::<Vec<_>>(), policy.urls.ocsp);
+
+        let text = String::from_utf8(cert.to_text().unwrap()).unwrap();
+        assert!(text.contains("CA Issuers - URI:http://pki.example.com/ca.crt"), "{}", text);
+        assert!(text.contains("URI:http://pki.example.com/ca.crl"), "{}", text);
+        assert!(text.contains("URI:ldap://ldap.example.com/cn=ca"), "{}", text);
+    }
+}
@@ -0,0 +1,224 @@
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::error::ErrorStack;
+
+/// Seconds since the Unix epoch of an ASN.1 time
+pub fn asn1_to_unix(time: &Asn1TimeRef) -> Result<i64, ErrorStack> {
+    let epoch = Asn1Time::from_unix(0)?;
+    let diff = epoch.diff(time)?;
+
+    Ok(i64::from(diff.days) * 86400 + i64::from(diff.secs))
+}
+
+/// Civil date `(year, month, day)` of a day count relative to 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+/// Format a Unix timestamp the way X.509 encodes it: `YYMMDDHHMMSSZ` (UTCTime) until 2049,
+/// `YYYYMMDDHHMMSSZ` (GeneralizedTime) afterwards
+pub fn unix_to_asn1_string(time: i64) -> String {
+    let (year, month, day) = civil_from_days(time.div_euclid(86400));
+    let secs = time.rem_euclid(86400);
+    let (hour, minute, second) = (secs / 3600, secs % 3600 / 60, secs % 60);
+
+    if (1950..2050).contains(&year) {
+        format!("{:02}{:02}{:02}{:02}{:02}{:02}Z", year % 100, month, day, hour, minute, second)
+    } else {
+        format!("{:04}{:02}{:02}{:02}{:02}{:02}Z", year, month, day, hour, minute, second)
+    }
+}
+
+/// Format an ASN.1 time as `YYMMDDHHMMSSZ`, the form used in the CA index
+pub fn asn1_to_string(time: &Asn1TimeRef) -> Result<String, ErrorStack> {
+    Ok(unix_to_asn1_string(asn1_to_unix(time)?))
+}
+
+/// Format an ASN.1 time as RFC 3339, e.g. `2026-10-18T12:00:00Z`
+pub fn asn1_to_rfc3339(time: &Asn1TimeRef) -> Result<String, ErrorStack> {
+    let time = asn1_to_unix(time)?;
+    let (year, month, day) = civil_from_days(time.div_euclid(86400));
+    let secs = time.rem_euclid(86400);
+
+    Ok(format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, secs / 3600, secs % 3600 / 60, secs % 60,
+    ))
+}
+
+/// Day count relative to 1970-01-01 of a civil date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from(if month > 2 { month - 3 } else { month + 9 });
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        _ => 31,
+    }
+}
+
+/// Unix timestamp of a civil date and time, checking that every field is in range
+fn unix_from_fields(year: i64, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> Option<i64> {
+    if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) ||
+        hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let second = second.min(59);
+
+    Some(days_from_civil(year, month, day) * 86400 + i64::from(hour * 3600 + minute * 60 + second))
+}
+
+fn number<T: std::str::FromStr>(value: &str) -> Option<T> {
+    if !value.is_empty() && value.bytes().all(|x| x.is_ascii_digit()) { value.parse().ok() } else { None }
+}
+
+/// Seconds of a duration such as `90d`, `12h`, `15m`, `30s`, `2w` or `1d12h`
+fn parse_duration(value: &str) -> Option<i64> {
+    let mut total = 0i64;
+    let mut rest = value;
+
+    while !rest.is_empty() {
+        let digits = rest.bytes().take_while(|x| x.is_ascii_digit()).count();
+        let count = number::<i64>(&rest[..digits])?;
+        let unit = match rest[digits..].chars().next()? {
+            'w' => 7 * 86400,
+            'd' => 86400,
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+
+        total = total.checked_add(count.checked_mul(unit)?)?;
+        rest = &rest[digits + 1..];
+    }
+
+    if value.is_empty() { None } else { Some(total) }
+}
+
+/// Unix timestamp of an ASN.1 time string, `YYMMDDHHMMSSZ` or `YYYYMMDDHHMMSSZ`
+fn parse_asn1(value: &str) -> Option<i64> {
+    let digits = value.strip_suffix('Z')?;
+
+    let (year, rest) = match digits.len() {
+        12 => {
+            let year = number::<i64>(&digits[..2])?;
+            (if year < 50 { 2000 + year } else { 1900 + year }, &digits[2..])
+        }
+        14 => (number::<i64>(&digits[..4])?, &digits[4..]),
+        _ => return None,
+    };
+
+    let field = |idx: usize| number::<u32>(&rest[idx..idx + 2]);
+
+    unix_from_fields(year, field(0)?, field(2)?, field(4)?, field(6)?, field(8)?)
+}
+
+/// Unix timestamp of an RFC 3339 timestamp such as `2027-01-01T00:00:00Z` or
+/// `2027-01-01T09:30:00.5+02:00`; a bare date means its midnight UTC
+fn parse_rfc3339(value: &str) -> Option<i64> {
+    let bytes = value.as_bytes();
+
+    if bytes.len() < 10 || bytes[4] != b'-' || bytes[7] != b'-' {
+        return None;
+    }
+
+    let year = number::<i64>(&value[..4])?;
+    let month = number::<u32>(&value[5..7])?;
+    let day = number::<u32>(&value[8..10])?;
+
+    if bytes.len() == 10 {
+        return unix_from_fields(year, month, day, 0, 0, 0);
+    }
+
+    if bytes.len() < 20 || !matches!(bytes[10], b'T' | b't' | b' ') || bytes[13] != b':' || bytes[16] != b':' {
+        return None;
+    }
+
+    let hour = number::<u32>(&value[11..13])?;
+    let minute = number::<u32>(&value[14..16])?;
+    let second = number::<u32>(&value[17..19])?;
+
+    let mut rest = &value[19..];
+
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(|x| x.is_ascii_digit()).count();
+
+        if digits == 0 {
+            return None;
+        }
+
+        rest = &fraction[digits..];
+    }
+
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let hours = number::<i64>(&rest[1..3])?;
+            let minutes = number::<i64>(&rest[4..6])?;
+
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+
+            sign * (hours * 3600 + minutes * 60)
+        }
+        _ => return None,
+    };
+
+    Some(unix_from_fields(year, month, day, hour, minute, second)? - offset)
+}
+
+/// Unix timestamp of a validity bound given on the command line, relative to `now`.
+///
+/// Accepts a whole number of days, a duration such as `90d`, `12h` or `15m`, either of them
+/// negative to go back from `now`, an RFC 3339 timestamp or an ASN.1 time string.
+pub fn parse_time(value: &str, now: i64) -> Option<i64> {
+    let value = value.trim();
+
+    // the parsers below slice by byte offsets
+    if !value.is_ascii() {
+        return None;
+    }
+
+    let (sign, offset) = match value.as_bytes().first() {
+        Some(b'-') => (-1, &value[1..]),
+        Some(b'+') => (1, &value[1..]),
+        _ => (1, value),
+    };
+
+    if let Some(days) = number::<i64>(offset) {
+        return days.checked_mul(86400 * sign)?.checked_add(now);
+    }
+
+    if let Some(secs) = parse_duration(offset) {
+        return secs.checked_mul(sign)?.checked_add(now);
+    }
+
+    parse_asn1(value).or_else(|| parse_rfc3339(value))
+}
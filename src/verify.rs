@@ -0,0 +1,167 @@
+//! Certificate chain verification for `simpleca verify`
+
+use openssl::error::ErrorStack;
+use openssl::stack::Stack;
+use openssl::x509::{X509, X509Ref, X509PurposeId, X509StoreContext};
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::{X509CheckFlags, X509VerifyFlags, X509VerifyParam};
+use std::net::IpAddr;
+
+use crate::name_to_string;
+
+/// Use a certificate is checked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    Server,
+    Client,
+}
+
+impl Purpose {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "server" => Some(Purpose::Server),
+            "client" => Some(Purpose::Client),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Purpose::Server => "server",
+            Purpose::Client => "client",
+        }
+    }
+
+    fn id(self) -> X509PurposeId {
+        match self {
+            Purpose::Server => X509PurposeId::SSL_SERVER,
+            Purpose::Client => X509PurposeId::SSL_CLIENT,
+        }
+    }
+}
+
+/// What a certificate is checked against besides its chain
+#[derive(Debug, Clone, Default)]
+pub struct VerifyOptions {
+    pub purpose: Option<Purpose>,
+    pub host: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+/// Outcome of `verify_chain`
+#[derive(Debug)]
+pub struct Verification {
+    /// Certificates from the leaf up to the trust anchor, as far as a path could be built
+    pub path: Vec<X509>,
+    /// Every problem found, empty when the certificate is valid for the requested use
+    pub errors: Vec<String>,
+}
+
+impl Verification {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Verify `cert` once against `trusted` and `untrusted` with the checks of `param`, returning the
+/// error, if any, and the chain
+fn run(
+    cert: &X509Ref,
+    trusted: &[X509],
+    untrusted: &[X509],
+    param: Option<X509VerifyParam>,
+) -> Result<(Option<String>, Vec<X509>), ErrorStack> {
+    let mut store = X509StoreBuilder::new()?;
+
+    for x in trusted {
+        store.add_cert(x.clone())?;
+    }
+
+    if let Some(param) = param {
+        store.set_param(&param)?;
+    }
+
+    let store = store.build();
+
+    let mut chain = Stack::<X509>::new()?;
+
+    for x in untrusted {
+        chain.push(x.clone())?;
+    }
+
+    let mut ctx = X509StoreContext::new()?;
+
+    ctx.init(&store, cert, &chain, |c| {
+        let error = if c.verify_cert()? {
+            None
+        } else {
+            let subject = c.current_cert()
+                .map(|x| name_to_string(x.subject_name()))
+                .unwrap_or_default();
+
+            Some(format!("{} at depth {} ({})", c.error().error_string(), c.error_depth(), subject))
+        };
+
+        let path = c.chain()
+            .map(|x| x.iter().map(|x| x.to_owned()).collect())
+            .unwrap_or_default();
+
+        Ok((error, path))
+    })
+}
+
+/// Whether the host name or IP address set in `param` matches `cert`.
+///
+/// The certificate is checked on its own as a trusted partial chain, so the result does not
+/// depend on whether it chains to a trust anchor or is within its validity.
+fn matches_id(cert: &X509Ref, mut param: X509VerifyParam) -> Result<bool, ErrorStack> {
+    param.set_flags(X509VerifyFlags::PARTIAL_CHAIN | X509VerifyFlags::NO_CHECK_TIME)?;
+
+    let (error, _) = run(cert, &[cert.to_owned()], &[], Some(param))?;
+
+    Ok(error.is_none())
+}
+
+/// Verify that `cert` chains to one of `trusted`, possibly through `untrusted` intermediates,
+/// and that it suits `options`
+pub fn verify_chain(
+    cert: &X509Ref,
+    trusted: &[X509],
+    untrusted: &[X509],
+    options: &VerifyOptions,
+) -> Result<Verification, ErrorStack> {
+    let (error, path) = run(cert, trusted, untrusted, None)?;
+    let mut errors = error.iter().cloned().collect::<Vec<_>>();
+
+    if let Some(purpose) = options.purpose {
+        let mut param = X509VerifyParam::new()?;
+        param.set_purpose(purpose.id())?;
+
+        let (purpose_error, _) = run(cert, trusted, untrusted, Some(param))?;
+
+        if let Some(purpose_error) = purpose_error.filter(|x| Some(x) != error.as_ref()) {
+            errors.push(format!("not valid for {} use: {}", purpose.name(), purpose_error));
+        }
+    }
+
+    if let Some(host) = &options.host {
+        let mut param = X509VerifyParam::new()?;
+        param.set_hostflags(X509CheckFlags::NEVER_CHECK_SUBJECT);
+        param.set_host(host)?;
+
+        if !matches_id(cert, param)? {
+            errors.push(format!("host name {} does not match the certificate", host));
+        }
+    }
+
+    if let Some(ip) = options.ip {
+        let mut param = X509VerifyParam::new()?;
+        param.set_ip(ip)?;
+
+        if !matches_id(cert, param)? {
+            errors.push(format!("IP address {} does not match the certificate", ip));
+        }
+    }
+
+    Ok(Verification { path, errors })
+}
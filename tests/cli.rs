@@ -0,0 +1,60 @@
+//! Exit codes and messages of the command line tool, as documented in the README
+
+use std::path::Path;
+use std::process::{Command, Output};
+use tempfile::tempdir;
+
+fn simpleca(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_simpleca"))
+        .current_dir(dir)
+        .env("SIMPLECA_PASSPHRASE", "right")
+        .env("SIMPLECA_WRONG", "wrong")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn assert_fails(output: &Output, code: i32, message: &str) {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert_eq!(output.status.code(), Some(code), "{}", stderr);
+    assert!(stderr.contains(message), "{}", stderr);
+}
+
+#[test]
+fn test_existing_output() {
+    let dir = tempdir().unwrap();
+
+    assert!(simpleca(dir.path(), &["key", "gen", "--algo", "ec", "key.pem"]).status.success());
+
+    let output = simpleca(dir.path(), &["key", "gen", "--algo", "ec", "key.pem"]);
+    assert_fails(&output, 4, "key.pem: already exists, pass --force or --backup to replace it");
+
+    assert!(simpleca(dir.path(), &["key", "gen", "--algo", "ec", "key.pem", "--force"]).status.success());
+}
+
+#[test]
+fn test_missing_input() {
+    let dir = tempdir().unwrap();
+
+    let output = simpleca(dir.path(), &["ca", "-N", "ca", "missing.pem", "ca.crt"]);
+    assert_fails(&output, 3, "missing.pem: no such file");
+    assert!(!dir.path().join("ca.crt").exists());
+}
+
+#[test]
+fn test_wrong_passphrase() {
+    let dir = tempdir().unwrap();
+
+    let output = simpleca(dir.path(), &["key", "gen", "--algo", "ec", "--passphrase-env", "SIMPLECA_PASSPHRASE", "key.pem"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let output = simpleca(dir.path(), &["ca", "-N", "ca", "key.pem", "ca.crt"]);
+    assert_fails(&output, 5, "key.pem: the private key is encrypted and no passphrase was given");
+
+    let output = simpleca(dir.path(), &["ca", "-N", "ca", "--passphrase-env", "SIMPLECA_WRONG", "key.pem", "ca.crt"]);
+    assert_fails(&output, 5, "key.pem: wrong passphrase");
+
+    let output = simpleca(dir.path(), &["ca", "-N", "ca", "--passphrase-env", "SIMPLECA_PASSPHRASE", "key.pem", "ca.crt"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}